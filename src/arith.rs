@@ -0,0 +1,351 @@
+//! Integer arithmetic with the C-like operators shells use for `declare -i`
+//! variables: literals in any base, variable references, and the usual
+//! unary, binary and ternary operators on 64-bit integers.
+
+/// Evaluates `expr`, resolving bare names through `lookup`. Unset or empty
+/// variables count as 0; a variable whose value is itself an expression is
+/// evaluated recursively.
+pub fn evaluate(expr: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<i64, String> {
+    evaluate_nested(expr, lookup, 0)
+}
+
+const MAX_DEPTH: usize = 32;
+
+fn evaluate_nested(
+    expr: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    depth: usize,
+) -> Result<i64, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("{}: expression recursion level exceeded", expr.trim()));
+    }
+    let mut parser = Parser {
+        expr,
+        chars: expr.chars().collect(),
+        pos: 0,
+        noeval: 0,
+        lookup,
+        depth,
+    };
+    parser.skip_whitespace();
+    if parser.pos == parser.chars.len() {
+        return Ok(0);
+    }
+    let value = parser.comma()?;
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        return Err(parser.error("syntax error in expression"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    expr: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+    /// Non-zero while parsing the side of `&&`, `||` or `?:` that is not
+    /// taken, where errors such as division by zero must not be reported.
+    noeval: usize,
+    lookup: &'a dyn Fn(&str) -> Option<String>,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        let token: String = self.chars[self.pos.min(self.chars.len())..].iter().collect();
+        format!(
+            "{}: {} (error token is \"{}\")",
+            self.expr.trim(),
+            message,
+            token.trim()
+        )
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.pos).is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consumes `op` if it comes next and is not the start of a longer
+    /// operator listed in `unless`.
+    fn eat(&mut self, op: &str, unless: &[&str]) -> bool {
+        self.skip_whitespace();
+        let rest: String = self.chars[self.pos..].iter().take(3).collect();
+        if rest.starts_with(op) && !unless.iter().any(|longer| rest.starts_with(longer)) {
+            self.pos += op.chars().count();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn comma(&mut self) -> Result<i64, String> {
+        let mut value = self.ternary()?;
+        while self.eat(",", &[]) {
+            value = self.ternary()?;
+        }
+        Ok(value)
+    }
+
+    fn ternary(&mut self) -> Result<i64, String> {
+        let condition = self.logical_or()?;
+        if !self.eat("?", &[]) {
+            return Ok(condition);
+        }
+        if condition == 0 {
+            self.noeval += 1;
+        }
+        let if_true = self.ternary()?;
+        if condition == 0 {
+            self.noeval -= 1;
+        }
+        if !self.eat(":", &[]) {
+            return Err(self.error("`:' expected for conditional expression"));
+        }
+        if condition != 0 {
+            self.noeval += 1;
+        }
+        let if_false = self.ternary()?;
+        if condition != 0 {
+            self.noeval -= 1;
+        }
+        Ok(if condition != 0 { if_true } else { if_false })
+    }
+
+    fn logical_or(&mut self) -> Result<i64, String> {
+        let mut value = self.logical_and()?;
+        while self.eat("||", &[]) {
+            let skip = value != 0;
+            self.noeval += skip as usize;
+            let rhs = self.logical_and()?;
+            self.noeval -= skip as usize;
+            value = (value != 0 || rhs != 0) as i64;
+        }
+        Ok(value)
+    }
+
+    fn logical_and(&mut self) -> Result<i64, String> {
+        let mut value = self.bit_or()?;
+        while self.eat("&&", &[]) {
+            let skip = value == 0;
+            self.noeval += skip as usize;
+            let rhs = self.bit_or()?;
+            self.noeval -= skip as usize;
+            value = (value != 0 && rhs != 0) as i64;
+        }
+        Ok(value)
+    }
+
+    fn bit_or(&mut self) -> Result<i64, String> {
+        let mut value = self.bit_xor()?;
+        while self.eat("|", &["||"]) {
+            value |= self.bit_xor()?;
+        }
+        Ok(value)
+    }
+
+    fn bit_xor(&mut self) -> Result<i64, String> {
+        let mut value = self.bit_and()?;
+        while self.eat("^", &[]) {
+            value ^= self.bit_and()?;
+        }
+        Ok(value)
+    }
+
+    fn bit_and(&mut self) -> Result<i64, String> {
+        let mut value = self.equality()?;
+        while self.eat("&", &["&&"]) {
+            value &= self.equality()?;
+        }
+        Ok(value)
+    }
+
+    fn equality(&mut self) -> Result<i64, String> {
+        let mut value = self.comparison()?;
+        loop {
+            if self.eat("==", &[]) {
+                value = (value == self.comparison()?) as i64;
+            } else if self.eat("!=", &[]) {
+                value = (value != self.comparison()?) as i64;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn comparison(&mut self) -> Result<i64, String> {
+        let mut value = self.shift()?;
+        loop {
+            if self.eat("<=", &[]) {
+                value = (value <= self.shift()?) as i64;
+            } else if self.eat(">=", &[]) {
+                value = (value >= self.shift()?) as i64;
+            } else if self.eat("<", &["<<"]) {
+                value = (value < self.shift()?) as i64;
+            } else if self.eat(">", &[">>"]) {
+                value = (value > self.shift()?) as i64;
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn shift(&mut self) -> Result<i64, String> {
+        let mut value = self.additive()?;
+        loop {
+            if self.eat("<<", &[]) {
+                value = value.wrapping_shl(self.additive()? as u32);
+            } else if self.eat(">>", &[]) {
+                value = value.wrapping_shr(self.additive()? as u32);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn additive(&mut self) -> Result<i64, String> {
+        let mut value = self.multiplicative()?;
+        loop {
+            if self.eat("+", &["++"]) {
+                value = value.wrapping_add(self.multiplicative()?);
+            } else if self.eat("-", &["--"]) {
+                value = value.wrapping_sub(self.multiplicative()?);
+            } else {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn multiplicative(&mut self) -> Result<i64, String> {
+        let mut value = self.power()?;
+        loop {
+            let is_division = if self.eat("*", &["**"]) {
+                value = value.wrapping_mul(self.power()?);
+                continue;
+            } else if self.eat("/", &[]) {
+                true
+            } else if self.eat("%", &[]) {
+                false
+            } else {
+                return Ok(value);
+            };
+            self.skip_whitespace();
+            let rhs_start = self.pos;
+            let rhs = self.power()?;
+            if rhs == 0 {
+                if self.noeval == 0 {
+                    self.pos = rhs_start;
+                    return Err(self.error("division by 0"));
+                }
+                value = 0;
+            } else if is_division {
+                value = value.wrapping_div(rhs);
+            } else {
+                value = value.wrapping_rem(rhs);
+            }
+        }
+    }
+
+    fn power(&mut self) -> Result<i64, String> {
+        let base = self.unary()?;
+        if self.eat("**", &[]) {
+            // Right-associative: 2**3**2 is 2**(3**2).
+            let exponent = self.power()?;
+            if exponent < 0 {
+                if self.noeval == 0 {
+                    return Err(self.error("exponent less than 0"));
+                }
+                return Ok(0);
+            }
+            return Ok(base.wrapping_pow(exponent.min(u32::MAX as i64) as u32));
+        }
+        Ok(base)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        if self.eat("!", &["!="]) {
+            Ok((self.unary()? == 0) as i64)
+        } else if self.eat("~", &[]) {
+            Ok(!self.unary()?)
+        } else if self.eat("-", &["--"]) {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.eat("+", &["++"]) {
+            self.unary()
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        self.skip_whitespace();
+        if self.eat("(", &[]) {
+            let value = self.comma()?;
+            if !self.eat(")", &[]) {
+                return Err(self.error("missing `)'"));
+            }
+            return Ok(value);
+        }
+
+        let start = self.pos;
+        while self
+            .chars
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '#' || *c == '@')
+        {
+            self.pos += 1;
+        }
+        let word: String = self.chars[start..self.pos].iter().collect();
+
+        match word.chars().next() {
+            None => Err(self.error("syntax error: operand expected")),
+            Some(c) if c.is_ascii_digit() => {
+                parse_integer(&word).ok_or_else(|| {
+                    self.pos = start;
+                    self.error("value too great for base")
+                })
+            }
+            Some(_) if crate::vars::is_valid_name(&word) => {
+                let value = (self.lookup)(&word).unwrap_or_default();
+                if self.noeval > 0 {
+                    return Ok(0);
+                }
+                evaluate_nested(&value, self.lookup, self.depth + 1)
+            }
+            Some(_) => {
+                self.pos = start;
+                Err(self.error("syntax error: operand expected"))
+            }
+        }
+    }
+}
+
+/// Parses an integer constant: decimal, `0x` hex, leading-zero octal, or
+/// `base#digits` for bases 2 through 64.
+pub fn parse_integer(word: &str) -> Option<i64> {
+    let (base, digits) = if let Some((base, digits)) = word.split_once('#') {
+        (base.parse::<u32>().ok().filter(|b| (2..=64).contains(b))?, digits)
+    } else if let Some(hex) = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+        (16, hex)
+    } else if word.len() > 1 && word.starts_with('0') {
+        (8, &word[1..])
+    } else {
+        (10, word)
+    };
+
+    if digits.is_empty() {
+        return None;
+    }
+    digits.chars().try_fold(0i64, |acc, c| {
+        let digit = match c {
+            '0'..='9' => c as u32 - '0' as u32,
+            'a'..='z' => c as u32 - 'a' as u32 + 10,
+            'A'..='Z' if base <= 36 => c as u32 - 'A' as u32 + 10,
+            'A'..='Z' => c as u32 - 'A' as u32 + 36,
+            '@' => 62,
+            '_' => 63,
+            _ => return None,
+        };
+        (digit < base).then(|| acc.wrapping_mul(base as i64).wrapping_add(digit as i64))
+    })
+}
@@ -0,0 +1,493 @@
+//! Conditional expressions: the `test` and `[` builtins, which see their
+//! operands already expanded, and the `[[ ... ]]` compound command, which
+//! expands each operand itself so `==` can match patterns and `=~` regular
+//! expressions.
+
+use std::ffi::CString;
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+use crate::expand::{expand_pattern, expand_regex, parameter_value};
+use crate::{expand_string, pattern, Shell};
+
+const UNARY: [&str; 25] = [
+    "-a", "-b", "-c", "-d", "-e", "-f", "-g", "-h", "-k", "-n", "-o", "-p", "-r", "-s", "-t", "-u", "-v", "-w", "-x",
+    "-z", "-G", "-L", "-N", "-O", "-S",
+];
+
+const BINARY: [&str; 14] = [
+    "=", "==", "!=", "<", ">", "-eq", "-ne", "-lt", "-le", "-gt", "-ge", "-nt", "-ot", "-ef",
+];
+
+fn is_unary(op: &str) -> bool {
+    UNARY.contains(&op)
+}
+
+fn is_binary(op: &str) -> bool {
+    BINARY.contains(&op)
+}
+
+/// Evaluates the arguments of `test`, following the POSIX rules that decide
+/// by the number of arguments how they are read, and falling back to `!`,
+/// `-a`, `-o` and parentheses for longer expressions.
+pub fn test(shell: &Shell, args: &[&str]) -> Result<bool, String> {
+    match *args {
+        [] => Ok(false),
+        [word] => Ok(!word.is_empty()),
+        ["!", word] => Ok(word.is_empty()),
+        [op, operand] if is_unary(op) => unary(shell, op, operand),
+        [op, _] => Err(format!("{}: unary operator expected", op)),
+        [left, op, right] if is_binary(op) => binary(op, left, right),
+        [left, "-a", right] => Ok(!left.is_empty() && !right.is_empty()),
+        [left, "-o", right] => Ok(!left.is_empty() || !right.is_empty()),
+        ["!", ..] if args.len() <= 4 => test(shell, &args[1..]).map(|result| !result),
+        ["(", word, ")"] => Ok(!word.is_empty()),
+        ["(", left, right, ")"] => test(shell, &[left, right]),
+        [_, op, _] => Err(format!("{}: binary operator expected", op)),
+        _ => {
+            let mut parser = TestParser { shell, args, pos: 0 };
+            let result = parser.or()?;
+            match parser.args.get(parser.pos) {
+                Some(_) => Err("too many arguments".to_string()),
+                None => Ok(result),
+            }
+        }
+    }
+}
+
+/// Recursive descent over `test` arguments; `-a` binds tighter than `-o`.
+struct TestParser<'a> {
+    shell: &'a Shell,
+    args: &'a [&'a str],
+    pos: usize,
+}
+
+impl TestParser<'_> {
+    fn next(&mut self) -> Result<&str, String> {
+        let arg = self.args.get(self.pos).ok_or("argument expected")?;
+        self.pos += 1;
+        Ok(arg)
+    }
+
+    fn or(&mut self) -> Result<bool, String> {
+        let mut result = self.and()?;
+        while self.args.get(self.pos) == Some(&"-o") {
+            self.pos += 1;
+            result |= self.and()?;
+        }
+        Ok(result)
+    }
+
+    fn and(&mut self) -> Result<bool, String> {
+        let mut result = self.not()?;
+        while self.args.get(self.pos) == Some(&"-a") {
+            self.pos += 1;
+            result &= self.not()?;
+        }
+        Ok(result)
+    }
+
+    fn not(&mut self) -> Result<bool, String> {
+        if self.args.get(self.pos) == Some(&"!") {
+            self.pos += 1;
+            return self.not().map(|result| !result);
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<bool, String> {
+        let rest = &self.args[self.pos..];
+        match rest {
+            [left, op, right, ..] if is_binary(op) => {
+                self.pos += 3;
+                binary(op, left, right)
+            }
+            [op, operand, ..] if is_unary(op) => {
+                self.pos += 2;
+                unary(self.shell, op, operand)
+            }
+            ["(", ..] => {
+                self.pos += 1;
+                let result = self.or()?;
+                match self.next()? {
+                    ")" => Ok(result),
+                    arg => Err(format!("`)' expected, found {}", arg)),
+                }
+            }
+            _ => Ok(!self.next()?.is_empty()),
+        }
+    }
+}
+
+/// Evaluates the text between `[[` and `]]`.
+pub fn double_bracket(shell: &mut Shell, expression: &str) -> Result<bool, String> {
+    let tokens = lex(expression)?;
+    let mut parser = Parser { tokens: &tokens, pos: 0 };
+    let expr = parser.or()?;
+    if let Some(token) = tokens.get(parser.pos) {
+        return Err(format!("syntax error in conditional expression: unexpected token `{}'", token.text()));
+    }
+    evaluate(shell, &expr)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Token<'a> {
+    /// A word as written, still to be expanded.
+    Word(&'a str),
+    /// One of `&&`, `||`, `(`, `)`, `<` and `>`.
+    Operator(&'static str),
+}
+
+impl Token<'_> {
+    fn text(&self) -> &str {
+        match self {
+            Token::Word(word) => word,
+            Token::Operator(op) => op,
+        }
+    }
+}
+
+/// Splits the inside of `[[ ]]` into words and operators. The word after
+/// `=~` is a regular expression, where `(`, `)` and `|` are ordinary
+/// characters and blanks inside parentheses don't end it.
+fn lex(text: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let regex = tokens.last() == Some(&Token::Word("=~"));
+        let operator = ["&&", "||", "(", ")", "<", ">"]
+            .into_iter()
+            .find(|op| rest.starts_with(op) && !regex);
+        let len = match operator {
+            Some(op) => {
+                tokens.push(Token::Operator(op));
+                op.len()
+            }
+            None => {
+                let len = word_length(rest, regex)?;
+                tokens.push(Token::Word(&rest[..len]));
+                len
+            }
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+fn word_length(text: &str, regex: bool) -> Result<usize, String> {
+    let mut chars = text.char_indices().peekable();
+    let mut depth = 0;
+    while let Some((i, ch)) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            '\'' | '"' => loop {
+                match chars.next() {
+                    Some((_, '\\')) if ch == '"' => {
+                        chars.next();
+                    }
+                    Some((_, close)) if close == ch => break,
+                    Some(_) => {}
+                    None => return Err(format!("unexpected EOF while looking for matching `{}'", ch)),
+                }
+            },
+            '$' if chars.peek().is_some_and(|&(_, next)| next == '{') => {
+                let mut braces = 0;
+                for (_, ch) in chars.by_ref() {
+                    match ch {
+                        '{' => braces += 1,
+                        '}' if braces == 1 => break,
+                        '}' => braces -= 1,
+                        _ => {}
+                    }
+                }
+            }
+            '(' if regex => depth += 1,
+            ')' if regex && depth > 0 => depth -= 1,
+            _ if ch.is_whitespace() && depth == 0 => return Ok(i),
+            '&' | '|' | '(' | ')' | '<' | '>' if !regex => return Ok(i),
+            _ => {}
+        }
+    }
+    Ok(text.len())
+}
+
+enum Expr<'a> {
+    Not(Box<Expr<'a>>),
+    And(Box<Expr<'a>>, Box<Expr<'a>>),
+    Or(Box<Expr<'a>>, Box<Expr<'a>>),
+    Unary(&'a str, &'a str),
+    Binary(&'a str, &'a str, &'a str),
+    Word(&'a str),
+}
+
+struct Parser<'t, 'a> {
+    tokens: &'t [Token<'a>],
+    pos: usize,
+}
+
+impl<'a> Parser<'_, 'a> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn word(&mut self, after: &str) -> Result<&'a str, String> {
+        match self.peek() {
+            Some(Token::Word(word)) => {
+                self.pos += 1;
+                Ok(word)
+            }
+            Some(token) => Err(format!("unexpected token `{}', expected an argument to `{}'", token.text(), after)),
+            None => Err(format!("unexpected end of expression, expected an argument to `{}'", after)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr<'a>, String> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(Token::Operator("||")) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr<'a>, String> {
+        let mut expr = self.not()?;
+        while self.peek() == Some(Token::Operator("&&")) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<Expr<'a>, String> {
+        if self.peek() == Some(Token::Word("!")) {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr<'a>, String> {
+        match self.peek() {
+            Some(Token::Operator("(")) => {
+                self.pos += 1;
+                let expr = self.or()?;
+                if self.peek() != Some(Token::Operator(")")) {
+                    return Err("expected `)'".to_string());
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some(Token::Word(op)) if is_unary(op) && matches!(self.tokens.get(self.pos + 1), Some(Token::Word(_))) => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, self.word(op)?))
+            }
+            Some(Token::Word(left)) => {
+                self.pos += 1;
+                let op = match self.peek() {
+                    Some(Token::Word(op)) if is_binary(op) || op == "=~" => op,
+                    Some(Token::Operator(op)) if op == "<" || op == ">" => op,
+                    Some(Token::Word(word)) => return Err(format!("conditional binary operator expected, found `{}'", word)),
+                    _ => return Ok(Expr::Word(left)),
+                };
+                self.pos += 1;
+                Ok(Expr::Binary(left, op, self.word(op)?))
+            }
+            Some(token) => Err(format!("unexpected token `{}' in conditional command", token.text())),
+            None => Err("unexpected end of conditional expression".to_string()),
+        }
+    }
+}
+
+fn evaluate(shell: &mut Shell, expr: &Expr) -> Result<bool, String> {
+    match expr {
+        Expr::Not(expr) => evaluate(shell, expr).map(|result| !result),
+        Expr::And(left, right) => Ok(evaluate(shell, left)? && evaluate(shell, right)?),
+        Expr::Or(left, right) => Ok(evaluate(shell, left)? || evaluate(shell, right)?),
+        Expr::Unary(op, operand) => {
+            let operand = expand_string(operand, shell)?;
+            unary(shell, op, &operand)
+        }
+        Expr::Word(word) => Ok(!expand_string(word, shell)?.is_empty()),
+        Expr::Binary(left, op, right) => {
+            let left = expand_string(left, shell)?;
+            match *op {
+                "=" | "==" | "!=" => {
+                    let matched = pattern::matches(&expand_pattern(right, shell)?, &left);
+                    Ok(matched != (*op == "!="))
+                }
+                "=~" => {
+                    let regex = expand_regex(right, shell)?;
+                    regex_match(shell, &regex, &left)
+                }
+                "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => {
+                    let right = expand_string(right, shell)?;
+                    let lookup = |name: &str| parameter_value(shell, name);
+                    let left = crate::arith::evaluate(&left, &lookup)?;
+                    let right = crate::arith::evaluate(&right, &lookup)?;
+                    Ok(compare_integers(op, left, right))
+                }
+                _ => binary(op, &left, &expand_string(right, shell)?),
+            }
+        }
+    }
+}
+
+/// Matches `text` against the extended regular expression `regex`, putting
+/// the whole match and each group in `BASH_REMATCH`.
+fn regex_match(shell: &mut Shell, regex: &str, text: &str) -> Result<bool, String> {
+    let invalid = || format!("{}: invalid regular expression", regex);
+    let pattern = CString::new(regex).map_err(|_| invalid())?;
+    let subject = CString::new(text).map_err(|_| invalid())?;
+    let mut groups = Vec::new();
+    let matched = unsafe {
+        let mut compiled: libc::regex_t = std::mem::zeroed();
+        if libc::regcomp(&mut compiled, pattern.as_ptr(), libc::REG_EXTENDED) != 0 {
+            return Err(invalid());
+        }
+        let mut matches = vec![libc::regmatch_t { rm_so: -1, rm_eo: -1 }; group_count(regex) + 1];
+        let status = libc::regexec(&compiled, subject.as_ptr(), matches.len(), matches.as_mut_ptr(), 0);
+        libc::regfree(&mut compiled);
+        if status == 0 {
+            for group in &matches {
+                let range = usize::try_from(group.rm_so).ok().zip(usize::try_from(group.rm_eo).ok());
+                let value = range.map_or(String::new(), |(start, end)| {
+                    String::from_utf8_lossy(&text.as_bytes()[start..end]).into_owned()
+                });
+                groups.push((None, value));
+            }
+        }
+        status == 0
+    };
+    shell.vars.set_array("BASH_REMATCH", groups, false).map_err(|e| e.to_string())?;
+    Ok(matched)
+}
+
+/// The number of parenthesised groups in an extended regular expression,
+/// skipping escaped parentheses and those inside bracket expressions.
+fn group_count(regex: &str) -> usize {
+    let mut count = 0;
+    let mut chars = regex.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            '(' => count += 1,
+            '[' => {
+                // A `]` right after `[` or `[^` is part of the set.
+                chars.next_if_eq(&'^');
+                chars.next_if_eq(&']');
+                while let Some(ch) = chars.next() {
+                    match ch {
+                        ']' => break,
+                        '[' if chars.peek().is_some_and(|c| ":.=".contains(*c)) => {
+                            let delimiter = chars.next();
+                            while let Some(ch) = chars.next() {
+                                if Some(ch) == delimiter && chars.next_if_eq(&']').is_some() {
+                                    break;
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    count
+}
+
+fn unary(shell: &Shell, op: &str, operand: &str) -> Result<bool, String> {
+    Ok(match op {
+        "-n" => !operand.is_empty(),
+        "-z" => operand.is_empty(),
+        "-o" => shell.options.get(operand) == Some(true),
+        "-v" => match operand.split_once('[') {
+            Some((name, subscript)) => {
+                let subscript = subscript.strip_suffix(']').unwrap_or(subscript);
+                shell.vars.get_element(name, subscript).ok().flatten().is_some()
+            }
+            None => shell.vars.lookup(operand).is_some_and(|var| var.value.is_some()),
+        },
+        "-t" => {
+            let fd = integer(operand)?;
+            i32::try_from(fd).is_ok_and(|fd| unsafe { libc::isatty(fd) } == 1)
+        }
+        "-r" => access(operand, libc::R_OK),
+        "-w" => access(operand, libc::W_OK),
+        "-x" => access(operand, libc::X_OK),
+        "-h" | "-L" => fs::symlink_metadata(operand).is_ok_and(|meta| meta.file_type().is_symlink()),
+        _ => {
+            let Ok(meta) = fs::metadata(operand) else {
+                return Ok(false);
+            };
+            let file_type = meta.file_type();
+            match op {
+                "-a" | "-e" => true,
+                "-b" => file_type.is_block_device(),
+                "-c" => file_type.is_char_device(),
+                "-d" => file_type.is_dir(),
+                "-f" => file_type.is_file(),
+                "-p" => file_type.is_fifo(),
+                "-S" => file_type.is_socket(),
+                "-s" => meta.len() > 0,
+                "-g" => meta.mode() & 0o2000 != 0,
+                "-u" => meta.mode() & 0o4000 != 0,
+                "-k" => meta.mode() & 0o1000 != 0,
+                "-O" => meta.uid() == unsafe { libc::geteuid() },
+                "-G" => meta.gid() == unsafe { libc::getegid() },
+                "-N" => (meta.mtime(), meta.mtime_nsec()) > (meta.atime(), meta.atime_nsec()),
+                _ => return Err(format!("{}: unary operator expected", op)),
+            }
+        }
+    })
+}
+
+fn binary(op: &str, left: &str, right: &str) -> Result<bool, String> {
+    Ok(match op {
+        "=" | "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        ">" => left > right,
+        "-eq" | "-ne" | "-lt" | "-le" | "-gt" | "-ge" => compare_integers(op, integer(left)?, integer(right)?),
+        "-nt" | "-ot" => {
+            let modified = |path: &str| fs::metadata(path).and_then(|meta| meta.modified()).ok();
+            let (left, right) = (modified(left), modified(right));
+            if op == "-nt" {
+                left.is_some() && (right.is_none() || left > right)
+            } else {
+                right.is_some() && (left.is_none() || left < right)
+            }
+        }
+        "-ef" => match (fs::metadata(left), fs::metadata(right)) {
+            (Ok(left), Ok(right)) => left.dev() == right.dev() && left.ino() == right.ino(),
+            _ => false,
+        },
+        _ => return Err(format!("{}: binary operator expected", op)),
+    })
+}
+
+fn compare_integers(op: &str, left: i64, right: i64) -> bool {
+    match op {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        "-gt" => left > right,
+        _ => left >= right,
+    }
+}
+
+fn integer(word: &str) -> Result<i64, String> {
+    word.trim()
+        .parse()
+        .map_err(|_| format!("{}: integer expression expected", word))
+}
+
+fn access(path: &str, mode: libc::c_int) -> bool {
+    CString::new(path).is_ok_and(|path| unsafe { libc::access(path.as_ptr(), mode) } == 0)
+}
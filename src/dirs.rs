@@ -0,0 +1,130 @@
+//! Directory handling for `cd`, `pushd`, `popd` and `dirs`: logical paths,
+//! `$CDPATH` and the directory stack. The stack's first entry is always the
+//! current directory, `$PWD`, which the shell tracks itself; the stack only
+//! holds the directories saved behind it, most recent first.
+
+use std::path::Path;
+
+/// A stack entry named by `+N`, counting from the left of the `dirs`
+/// listing, or `-N`, counting from the right; both start at zero.
+#[derive(Clone, Copy)]
+pub struct Offset {
+    pub from_right: bool,
+    pub n: usize,
+}
+
+impl Offset {
+    /// Parses `+N` or `-N`. Returns `None` for anything else, and `Some(Err)`
+    /// when the sign is followed by something other than a number.
+    pub fn parse(arg: &str) -> Option<Result<Offset, ()>> {
+        let from_right = arg.starts_with('-');
+        if arg.len() < 2 || !(from_right || arg.starts_with('+')) {
+            return None;
+        }
+        Some(arg[1..].parse().map(|n| Offset { from_right, n }).map_err(|_| ()))
+    }
+}
+
+#[derive(Default)]
+pub struct DirStack {
+    saved: Vec<String>,
+}
+
+impl DirStack {
+    pub fn is_empty(&self) -> bool {
+        self.saved.is_empty()
+    }
+
+    /// Every entry, starting with the current directory `pwd`.
+    pub fn entries(&self, pwd: &str) -> Vec<String> {
+        std::iter::once(pwd.to_string()).chain(self.saved.iter().cloned()).collect()
+    }
+
+    /// The position `offset` names in the full listing, if it is in range.
+    pub fn index(&self, offset: Offset) -> Option<usize> {
+        let len = self.saved.len() + 1;
+        if offset.from_right {
+            len.checked_sub(offset.n + 1)
+        } else {
+            (offset.n < len).then_some(offset.n)
+        }
+    }
+
+    /// Saves `dir` at the top, behind the current directory.
+    pub fn push(&mut self, dir: String) {
+        self.saved.insert(0, dir);
+    }
+
+    /// Removes the entry at `index` of the full listing, which must not be
+    /// the current directory.
+    pub fn remove(&mut self, index: usize) -> String {
+        self.saved.remove(index - 1)
+    }
+
+    /// Replaces the saved entries with the rest of a full listing whose
+    /// first entry has become the current directory.
+    pub fn replace(&mut self, entries: Vec<String>) {
+        self.saved = entries.into_iter().skip(1).collect();
+    }
+
+    pub fn clear(&mut self) {
+        self.saved.clear();
+    }
+}
+
+/// Writes `path` with a leading `$HOME` replaced by `~`, as `dirs` shows it.
+pub fn abbreviate(path: &str, home: Option<&str>) -> String {
+    match home.filter(|home| !home.is_empty() && *home != "/") {
+        Some(home) if path == home => "~".to_string(),
+        Some(home) => match path.strip_prefix(home).filter(|rest| rest.starts_with('/')) {
+            Some(rest) => format!("~{}", rest),
+            None => path.to_string(),
+        },
+        None => path.to_string(),
+    }
+}
+
+/// Resolves `dir` against the logical current directory `pwd` without
+/// following symlinks: `.` components are dropped and `..` removes the
+/// component before it. Returns `None` when a `..` follows something that is
+/// not a directory, as the kernel would refuse that path.
+pub fn logical_path(pwd: &str, dir: &str) -> Option<String> {
+    let mut path = if dir.starts_with('/') { String::new() } else { pwd.trim_end_matches('/').to_string() };
+    for component in dir.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                if !Path::new(if path.is_empty() { "/" } else { &path }).is_dir() {
+                    return None;
+                }
+                path.truncate(path.rfind('/').unwrap_or(0));
+            }
+            _ => {
+                path.push('/');
+                path.push_str(component);
+            }
+        }
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    Some(path)
+}
+
+/// Looks for the relative directory `dir` under each entry of `cdpath`, an
+/// empty entry meaning the current directory. Returns the first match and
+/// whether `cd` should print it, which it does when the match came from a
+/// non-empty entry. Names starting with `/`, `.` or `..` are not searched.
+pub fn search_cdpath(cdpath: &str, dir: &str) -> Option<(String, bool)> {
+    let first = dir.split('/').next().unwrap_or("");
+    if dir.starts_with('/') || first == "." || first == ".." {
+        return None;
+    }
+    cdpath.split(':').find_map(|entry| {
+        let candidate = match entry {
+            "" => dir.to_string(),
+            _ => format!("{}/{}", entry.trim_end_matches('/'), dir),
+        };
+        Path::new(&candidate).is_dir().then_some((candidate, !entry.is_empty()))
+    })
+}
@@ -0,0 +1,66 @@
+//! Variables whose value is computed each time they are read: `RANDOM`,
+//! `SECONDS`, `LINENO` and the clock and process id variables. They keep
+//! their special meaning until unset, like any other shell variable.
+
+use std::cell::Cell;
+use std::process;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub const NAMES: [&str; 6] = ["BASHPID", "EPOCHREALTIME", "EPOCHSECONDS", "LINENO", "RANDOM", "SECONDS"];
+
+pub struct DynamicVars {
+    start: Instant,
+    /// The value `SECONDS` was last assigned, counted up from `start`.
+    seconds_offset: i64,
+    random: Cell<u32>,
+}
+
+impl DynamicVars {
+    pub fn new() -> Self {
+        let seed = since_epoch().subsec_nanos() ^ process::id();
+        DynamicVars { start: Instant::now(), seconds_offset: 0, random: Cell::new(seed) }
+    }
+
+    /// The current value of `name`, or `None` if it is not a dynamic
+    /// variable. `lineno` is the line being run, for `LINENO`.
+    pub fn value(&self, name: &str, lineno: usize) -> Option<String> {
+        let value = match name {
+            "BASHPID" => process::id().to_string(),
+            "EPOCHREALTIME" => {
+                let now = since_epoch();
+                format!("{}.{:06}", now.as_secs(), now.subsec_micros())
+            }
+            "EPOCHSECONDS" => since_epoch().as_secs().to_string(),
+            "LINENO" => lineno.to_string(),
+            "RANDOM" => self.next_random().to_string(),
+            "SECONDS" => (self.seconds_offset + self.start.elapsed().as_secs() as i64).to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Called after `name` is assigned `value`: assigning `RANDOM` seeds the
+    /// generator and assigning `SECONDS` restarts the count from `value`.
+    pub fn assigned(&mut self, name: &str, value: &str) {
+        match name {
+            "RANDOM" => self.random.set(value.parse::<i64>().unwrap_or(0) as u32),
+            "SECONDS" => {
+                self.start = Instant::now();
+                self.seconds_offset = value.parse().unwrap_or(0);
+            }
+            _ => {}
+        }
+    }
+
+    /// The next number from 0 to 32767, from the same linear congruential
+    /// generator bash uses.
+    fn next_random(&self) -> u32 {
+        let seed = self.random.get().wrapping_mul(1103515245).wrapping_add(12345);
+        self.random.set(seed);
+        (seed >> 16) & 0x7fff
+    }
+}
+
+fn since_epoch() -> std::time::Duration {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default()
+}
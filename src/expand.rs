@@ -0,0 +1,775 @@
+//! Parameter expansion: `$name`, `$1`, the special parameters, and the
+//! `${...}` forms including array subscripts, indirection, default values
+//! and the trimming, substitution, case and substring operators.
+
+use std::iter::Peekable;
+use std::str::Chars;
+
+use crate::pattern;
+use crate::vars::{is_valid_name, Value};
+use crate::{expand_string, Shell};
+
+/// The end of the error for expanding an unset parameter under `set -u`.
+pub const UNBOUND: &str = "unbound variable";
+
+/// The result of expanding one parameter.
+pub enum Expansion {
+    /// A single string, spliced into the word being built.
+    Text(String),
+    /// Separate fields, as `"$@"` and `"${name[@]}"` produce: the first joins
+    /// the text before the expansion and the last the text after it.
+    Fields(Vec<String>),
+}
+
+/// Expands the parameter named right after a `$`, consuming it from `chars`.
+/// Returns `Ok(None)` when the `$` does not introduce a parameter, in which
+/// case it is kept literally. `quoted` says whether the `$` appeared inside
+/// double quotes, which decides how `$*` and `${name[*]}` are joined.
+pub fn expand_parameter(
+    chars: &mut Peekable<Chars>,
+    shell: &mut Shell,
+    quoted: bool,
+) -> Result<Option<Expansion>, String> {
+    let name = match chars.peek().copied() {
+        Some(c) if c.is_ascii_digit() || "#@*?$!-".contains(c) => {
+            chars.next();
+            c.to_string()
+        }
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c);
+                chars.next();
+            }
+            name
+        }
+        Some('{') => {
+            chars.next();
+            let body = read_braced(chars).ok_or_else(|| "unexpected EOF while looking for matching `}'".to_string())?;
+            return expand_braced(&body, shell, quoted).map(Some);
+        }
+        _ => return Ok(None),
+    };
+
+    let param = Param { name, subscript: None };
+    let value = lookup(shell, &param)?;
+    Ok(Some(into_expansion(shell, value, quoted)))
+}
+
+/// Reads up to the `}` closing a `${`, skipping over nested braces and
+/// quoted text. Returns `None` if the input ends first.
+fn read_braced(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut body = String::new();
+    let mut depth = 0;
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if !in_single_quotes => {
+                body.push(ch);
+                body.push(chars.next()?);
+                continue;
+            }
+            '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+            '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+            '{' if !in_single_quotes && !in_double_quotes => depth += 1,
+            '}' if !in_single_quotes && !in_double_quotes => {
+                if depth == 0 {
+                    return Some(body);
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+        body.push(ch);
+    }
+    None
+}
+
+/// A parameter reference inside `${...}`: a name, positional number or
+/// special character, optionally followed by an array subscript.
+struct Param {
+    name: String,
+    subscript: Option<String>,
+}
+
+impl Param {
+    /// Whether this refers to a whole list (`@`, `*`, `name[@]`, `name[*]`)
+    /// and, if so, whether it was the `*` form.
+    fn list_kind(&self) -> Option<bool> {
+        match (self.name.as_str(), self.subscript.as_deref()) {
+            ("@", None) | (_, Some("@")) => Some(false),
+            ("*", None) | (_, Some("*")) => Some(true),
+            _ => None,
+        }
+    }
+}
+
+enum ParamValue {
+    Unset,
+    Single(String),
+    /// The elements of `$@` or an array, and whether it was the `*` form.
+    List(Vec<String>, bool),
+}
+
+/// Splits the parameter off the start of a `${...}` body, returning it and
+/// whatever operator text follows.
+fn parse_param(body: &str) -> Option<(Param, &str)> {
+    let first = body.chars().next()?;
+    let name_len = if first.is_ascii_digit() {
+        body.find(|c: char| !c.is_ascii_digit()).unwrap_or(body.len())
+    } else if "@*#?$!-".contains(first) {
+        1
+    } else {
+        body.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(body.len())
+    };
+    let name = &body[..name_len];
+    if name.is_empty() || (first.is_ascii_alphabetic() || first == '_') && !is_valid_name(name) {
+        return None;
+    }
+
+    let rest = &body[name_len..];
+    if !rest.starts_with('[') || !is_valid_name(name) {
+        return Some((Param { name: name.to_string(), subscript: None }, rest));
+    }
+
+    let mut depth = 0;
+    for (i, ch) in rest.char_indices() {
+        match ch {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth == 0 {
+                    let subscript = rest[1..i].to_string();
+                    return Some((Param { name: name.to_string(), subscript: Some(subscript) }, &rest[i + 1..]));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// The value of `param`. Under `set -u` an unset parameter is an error.
+fn lookup(shell: &mut Shell, param: &Param) -> Result<ParamValue, String> {
+    match value_of(shell, param)? {
+        ParamValue::Unset if shell.options.nounset => {
+            let name = match &param.subscript {
+                Some(subscript) => format!("{}[{}]", param.name, subscript),
+                None => param.name.clone(),
+            };
+            Err(format!("{}: {}", name, UNBOUND))
+        }
+        value => Ok(value),
+    }
+}
+
+/// The value of `param`, which may be unset whatever `set -u` says.
+fn value_of(shell: &mut Shell, param: &Param) -> Result<ParamValue, String> {
+    if let Some(star) = param.list_kind() {
+        let values = match &param.subscript {
+            None => shell.positional.clone(),
+            Some(_) => shell
+                .vars
+                .lookup(&param.name)
+                .and_then(|var| var.value.as_ref())
+                .map(|value| value.values())
+                .unwrap_or_default(),
+        };
+        return Ok(ParamValue::List(values, star));
+    }
+
+    let value = match &param.subscript {
+        Some(subscript) => {
+            let subscript = expand_string(subscript, shell)?;
+            shell
+                .vars
+                .get_element(&param.name, &subscript)
+                .map_err(|e| e.to_string())?
+        }
+        None => parameter_value(shell, &param.name),
+    };
+    Ok(value.map_or(ParamValue::Unset, ParamValue::Single))
+}
+
+fn into_expansion(shell: &Shell, value: ParamValue, quoted: bool) -> Expansion {
+    match value {
+        ParamValue::Unset => Expansion::Text(String::new()),
+        ParamValue::Single(value) => Expansion::Text(value),
+        // "$*" joins the elements with the first character of IFS.
+        ParamValue::List(values, true) if quoted => {
+            let separator: String = ifs(shell).chars().take(1).collect();
+            Expansion::Text(values.join(&separator))
+        }
+        ParamValue::List(values, _) => Expansion::Fields(values),
+    }
+}
+
+fn expand_braced(body: &str, shell: &mut Shell, quoted: bool) -> Result<Expansion, String> {
+    let bad_substitution = || format!("${{{}}}: bad substitution", body);
+
+    // ${#name}, ${#name[@]}: length of the value, or number of elements.
+    if let Some(rest) = body.strip_prefix('#').filter(|rest| !rest.is_empty()) {
+        let (param, tail) = parse_param(rest).ok_or_else(bad_substitution)?;
+        if !tail.is_empty() {
+            return Err(bad_substitution());
+        }
+        let length = match lookup(shell, &param)? {
+            ParamValue::Unset => 0,
+            ParamValue::Single(value) => value.chars().count(),
+            ParamValue::List(values, _) => values.len(),
+        };
+        return Ok(Expansion::Text(length.to_string()));
+    }
+
+    if let Some(rest) = body.strip_prefix('!').filter(|rest| !rest.is_empty()) {
+        // ${!prefix*}, ${!prefix@}: the names of the variables set with that prefix.
+        if let Some(prefix) = rest.strip_suffix(['*', '@']).filter(|prefix| is_valid_name(prefix)) {
+            let names = shell
+                .vars
+                .iter()
+                .filter(|(name, var)| name.starts_with(prefix) && var.value.is_some())
+                .map(|(name, _)| name.to_string())
+                .collect();
+            return Ok(into_expansion(shell, ParamValue::List(names, rest.ends_with('*')), quoted));
+        }
+
+        let (param, tail) = parse_param(rest).ok_or_else(bad_substitution)?;
+
+        // ${!name[@]}: the subscripts of an array.
+        if let Some(star) = param.list_kind().filter(|_| param.subscript.is_some()) {
+            if !tail.is_empty() {
+                return Err(bad_substitution());
+            }
+            let keys = shell
+                .vars
+                .lookup(&param.name)
+                .and_then(|var| var.value.as_ref())
+                .map(|value| value.keys())
+                .unwrap_or_default();
+            return Ok(into_expansion(shell, ParamValue::List(keys, star), quoted));
+        }
+
+        // ${!name}: the value of the parameter whose name is in `name`.
+        let ParamValue::Single(target) = lookup(shell, &param)? else {
+            return Err(format!("{}: invalid indirect expansion", param.name));
+        };
+        let target = match parse_param(&target) {
+            Some((target, "")) => target,
+            _ => return Err(format!("{}: invalid variable name", target)),
+        };
+        return apply_operator(shell, body, &target, tail, quoted);
+    }
+
+    let (param, tail) = parse_param(body).ok_or_else(bad_substitution)?;
+    apply_operator(shell, body, &param, tail, quoted)
+}
+
+/// Expands `param` and applies the operator in `tail`, if any. `body` is
+/// the whole `${...}` text, for error messages.
+fn apply_operator(shell: &mut Shell, body: &str, param: &Param, tail: &str, quoted: bool) -> Result<Expansion, String> {
+    // The `-`, `=`, `?` and `+` forms, which supply their own value for an
+    // unset parameter and so are exempt from `set -u`. With a `:` they treat
+    // an empty value as unset too; `${v:-1}` is one of these, not a
+    // substring, which needs `${v: -1}`.
+    let (colon, rest) = match tail.strip_prefix(':') {
+        Some(rest) => (true, rest),
+        None => (false, tail),
+    };
+    if let Some(op) = rest.chars().next().filter(|op| "-=?+".contains(*op)) {
+        let value = value_of(shell, param)?;
+        let value = alternative(shell, param, value, colon, op, &rest[1..])?;
+        return Ok(into_expansion(shell, value, quoted));
+    }
+
+    let value = lookup(shell, param)?;
+    let Some(op) = tail.chars().next() else {
+        return Ok(into_expansion(shell, value, quoted));
+    };
+    let operand = &tail[1..];
+    let value = match op {
+        // ${name:offset:length}
+        ':' => substring(shell, param, value, operand)?,
+        // ${name#pattern}, ${name##pattern}, ${name%pattern}, ${name%%pattern}
+        '#' | '%' => {
+            let (longest, operand) = match operand.strip_prefix(op) {
+                Some(operand) => (true, operand),
+                None => (false, operand),
+            };
+            let pattern = expand_pattern(operand, shell)?;
+            map_value(value, |text| remove_affix(text, &pattern, op == '#', longest))
+        }
+        // ${name/pattern/string} and its `//`, `/#` and `/%` forms.
+        '/' => {
+            let (mode, operand) = match operand.chars().next() {
+                Some('/') => (Replace::All, &operand[1..]),
+                Some('#') => (Replace::Prefix, &operand[1..]),
+                Some('%') => (Replace::Suffix, &operand[1..]),
+                _ => (Replace::First, operand),
+            };
+            let (pattern, replacement) = split_replacement(operand);
+            let pattern = expand_pattern(pattern, shell)?;
+            let replacement = expand_string(replacement, shell)?;
+            map_value(value, |text| replace(text, &pattern, &replacement, mode))
+        }
+        // ${name^pattern}, ${name^^pattern}, ${name,pattern}, ${name,,pattern}
+        '^' | ',' => {
+            let (all, operand) = match operand.strip_prefix(op) {
+                Some(operand) => (true, operand),
+                None => (false, operand),
+            };
+            let pattern = expand_pattern(operand, shell)?;
+            map_value(value, |text| convert_case(text, &pattern, op == '^', all))
+        }
+        _ => return Err(format!("${{{}}}: bad substitution", body)),
+    };
+    Ok(into_expansion(shell, value, quoted))
+}
+
+/// `${name-word}`, `${name=word}`, `${name?word}` and `${name+word}`, or with
+/// `colon` their `:` forms. `word` is only expanded when it is used.
+fn alternative(
+    shell: &mut Shell,
+    param: &Param,
+    value: ParamValue,
+    colon: bool,
+    op: char,
+    word: &str,
+) -> Result<ParamValue, String> {
+    let set = match &value {
+        ParamValue::Unset => false,
+        ParamValue::Single(text) => !(colon && text.is_empty()),
+        ParamValue::List(values, _) => !(values.is_empty() || colon && values.iter().all(String::is_empty)),
+    };
+    match op {
+        '+' if set => Ok(ParamValue::Single(expand_string(word, shell)?)),
+        '+' => Ok(ParamValue::Single(String::new())),
+        _ if set => Ok(value),
+        '-' => Ok(ParamValue::Single(expand_string(word, shell)?)),
+        '=' => {
+            if !is_valid_name(&param.name) || param.list_kind().is_some() {
+                return Err(format!("${}: cannot assign in this way", param.name));
+            }
+            let word = expand_string(word, shell)?;
+            let result = match &param.subscript {
+                Some(subscript) => {
+                    let subscript = expand_string(subscript, shell)?;
+                    shell.vars.set_element(&param.name, &subscript, word.clone())
+                }
+                None => shell.vars.set(&param.name, word.clone()),
+            };
+            result.map_err(|e| e.to_string())?;
+            crate::after_assignment(shell, &param.name);
+            Ok(ParamValue::Single(word))
+        }
+        _ => {
+            let message = match expand_string(word, shell)? {
+                message if message.is_empty() && colon => "parameter null or not set".to_string(),
+                message if message.is_empty() => "parameter not set".to_string(),
+                message => message,
+            };
+            Err(format!("{}: {}", param.name, message))
+        }
+    }
+}
+
+/// Applies `f` to a single value, or to each element of a list.
+fn map_value(value: ParamValue, f: impl Fn(&str) -> String) -> ParamValue {
+    match value {
+        ParamValue::Unset => ParamValue::Unset,
+        ParamValue::Single(text) => ParamValue::Single(f(&text)),
+        ParamValue::List(values, star) => ParamValue::List(values.iter().map(|text| f(text)).collect(), star),
+    }
+}
+
+/// `${name:offset:length}`. A string is sliced by characters, a list by
+/// elements; a negative offset counts back from the end. An indexed array is
+/// sliced by index, so the offset picks the first element set at or after
+/// it and the length counts only the elements that are set.
+fn substring(shell: &mut Shell, param: &Param, value: ParamValue, spec: &str) -> Result<ParamValue, String> {
+    let (offset, length) = match spec.split_once(':') {
+        Some((offset, length)) => (offset, Some(length)),
+        None => (spec, None),
+    };
+    let offset = arithmetic(shell, offset)?;
+    let length = length.map(|length| arithmetic(shell, length)).transpose()?;
+
+    match value {
+        ParamValue::Unset => Ok(ParamValue::Unset),
+        ParamValue::Single(text) => {
+            let chars: Vec<char> = text.chars().collect();
+            let len = chars.len() as i64;
+            let start = if offset < 0 { len + offset } else { offset };
+            if start < 0 || start > len {
+                return Ok(ParamValue::Single(String::new()));
+            }
+            // For strings a negative length is an offset from the end.
+            let end = match length {
+                None => len,
+                Some(length) if length < 0 => len + length,
+                Some(length) => (start + length).min(len),
+            };
+            if end < start {
+                return Err(format!("{}: substring expression < 0", length.unwrap_or_default()));
+            }
+            Ok(ParamValue::Single(chars[start as usize..end as usize].iter().collect()))
+        }
+        ParamValue::List(mut values, star) => {
+            // The positional parameters are numbered from 1, so offset 0 is $0.
+            if param.subscript.is_none() {
+                values.insert(0, shell.name.clone());
+            }
+            let indices: Vec<i64> = match shell.vars.lookup(&param.name).and_then(|var| var.value.as_ref()) {
+                Some(Value::Indexed(elements)) if param.subscript.is_some() => {
+                    elements.keys().map(|&index| index as i64).collect()
+                }
+                _ => (0..values.len() as i64).collect(),
+            };
+            let end = indices.last().map_or(0, |last| last + 1);
+            let start = if offset < 0 { end + offset } else { offset };
+            if start < 0 {
+                return Ok(ParamValue::List(Vec::new(), star));
+            }
+            let skipped = indices.iter().take_while(|&&index| index < start).count();
+            let mut slice: Vec<String> = values.into_iter().skip(skipped).collect();
+            if let Some(length) = length {
+                if length < 0 {
+                    return Err(format!("{}: substring expression < 0", length));
+                }
+                slice.truncate(length as usize);
+            }
+            Ok(ParamValue::List(slice, star))
+        }
+    }
+}
+
+/// Expands the pattern operand of `#`, `%`, `/`, `^` and `,`. Parameters are
+/// expanded and quotes removed; quoted text is escaped so it only matches
+/// itself, while unquoted text and expansions keep their pattern meaning.
+pub fn expand_pattern(text: &str, shell: &mut Shell) -> Result<String, String> {
+    expand_escaping(text, shell, pattern::escape)
+}
+
+/// Expands the right-hand side of `=~` the same way, escaping quoted text
+/// for an extended regular expression instead.
+pub fn expand_regex(text: &str, shell: &mut Shell) -> Result<String, String> {
+    expand_escaping(text, shell, |text| {
+        let mut escaped = String::with_capacity(text.len());
+        for ch in text.chars() {
+            if "\\.[]()*+?{}|^$".contains(ch) {
+                escaped.push('\\');
+            }
+            escaped.push(ch);
+        }
+        escaped
+    })
+}
+
+fn expand_escaping(text: &str, shell: &mut Shell, escape: fn(&str) -> String) -> Result<String, String> {
+    let mut pattern = String::new();
+    let mut chars = text.chars().peekable();
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+            '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+            '\\' if !in_single_quotes => match chars.next() {
+                Some(next) if in_double_quotes && !"\\\"$".contains(next) => {
+                    pattern.push_str(&escape("\\"));
+                    pattern.push_str(&escape(&next.to_string()));
+                }
+                Some(next) => pattern.push_str(&escape(&next.to_string())),
+                None => pattern.push_str(&escape("\\")),
+            },
+            '$' if !in_single_quotes => {
+                let value = match expand_parameter(&mut chars, shell, in_double_quotes)? {
+                    Some(Expansion::Text(value)) => value,
+                    Some(Expansion::Fields(fields)) => fields.join(" "),
+                    None => "$".to_string(),
+                };
+                if in_double_quotes {
+                    pattern.push_str(&escape(&value));
+                } else {
+                    pattern.push_str(&value);
+                }
+            }
+            _ if in_single_quotes || in_double_quotes => pattern.push_str(&escape(&ch.to_string())),
+            _ => pattern.push(ch),
+        }
+    }
+    Ok(pattern)
+}
+
+/// Splits `pattern/string` at the first unquoted, unescaped `/`. Without
+/// a `/` the replacement is empty.
+fn split_replacement(operand: &str) -> (&str, &str) {
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+    let mut escaped = false;
+    for (i, ch) in operand.char_indices() {
+        match ch {
+            _ if escaped => escaped = false,
+            '\\' if !in_single_quotes => escaped = true,
+            '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+            '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+            '/' if !in_single_quotes && !in_double_quotes => return (&operand[..i], &operand[i + 1..]),
+            _ => {}
+        }
+    }
+    (operand, "")
+}
+
+/// The byte offset of every character boundary in `text`, including the end.
+fn boundaries(text: &str) -> Vec<usize> {
+    text.char_indices().map(|(i, _)| i).chain([text.len()]).collect()
+}
+
+/// Removes the shortest or longest prefix (`#`) or suffix (`%`) of `text`
+/// that matches `pattern`.
+fn remove_affix(text: &str, pattern: &str, prefix: bool, longest: bool) -> String {
+    let bounds = boundaries(text);
+    let mut cuts: Box<dyn Iterator<Item = usize>> = match (prefix, longest) {
+        (true, false) | (false, true) => Box::new(bounds.iter().copied()),
+        (true, true) | (false, false) => Box::new(bounds.iter().rev().copied()),
+    };
+    let cut = if prefix {
+        cuts.find(|&cut| pattern::matches(pattern, &text[..cut]))
+    } else {
+        cuts.find(|&cut| pattern::matches(pattern, &text[cut..]))
+    };
+    match cut {
+        Some(cut) if prefix => text[cut..].to_string(),
+        Some(cut) => text[..cut].to_string(),
+        None => text.to_string(),
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Replace {
+    First,
+    All,
+    Prefix,
+    Suffix,
+}
+
+/// Replaces the longest match of `pattern` in `text`: the first one, every
+/// one, or only a match anchored at the start or end.
+fn replace(text: &str, pattern: &str, replacement: &str, mode: Replace) -> String {
+    let bounds = boundaries(text);
+    let longest_from = |start: usize| {
+        bounds
+            .iter()
+            .rev()
+            .copied()
+            .take_while(|&end| end >= start)
+            .find(|&end| pattern::matches(pattern, &text[start..end]))
+    };
+
+    match mode {
+        Replace::Prefix => match longest_from(0) {
+            Some(end) => format!("{}{}", replacement, &text[end..]),
+            None => text.to_string(),
+        },
+        Replace::Suffix => match bounds.iter().find(|&&start| pattern::matches(pattern, &text[start..])) {
+            Some(&start) => format!("{}{}", &text[..start], replacement),
+            None => text.to_string(),
+        },
+        Replace::First | Replace::All => {
+            if pattern.is_empty() {
+                return text.to_string();
+            }
+            let mut result = String::new();
+            let mut i = 0;
+            while i < bounds.len() - 1 {
+                let start = bounds[i];
+                match longest_from(start).filter(|&end| end > start) {
+                    Some(end) => {
+                        result.push_str(replacement);
+                        if mode == Replace::First {
+                            result.push_str(&text[end..]);
+                            return result;
+                        }
+                        i = bounds.partition_point(|&bound| bound < end);
+                    }
+                    None => {
+                        result.push_str(&text[start..bounds[i + 1]]);
+                        i += 1;
+                    }
+                }
+            }
+            result
+        }
+    }
+}
+
+/// `^`/`,` upper- or lower-case the first character, `^^`/`,,` every one;
+/// a non-empty pattern limits the change to characters it matches.
+fn convert_case(text: &str, pattern: &str, upper: bool, all: bool) -> String {
+    let mut result = String::with_capacity(text.len());
+    for (i, ch) in text.chars().enumerate() {
+        let selected = (all || i == 0) && (pattern.is_empty() || pattern::matches(pattern, &ch.to_string()));
+        match (selected, upper) {
+            (true, true) => result.extend(ch.to_uppercase()),
+            (true, false) => result.extend(ch.to_lowercase()),
+            (false, _) => result.push(ch),
+        }
+    }
+    result
+}
+
+fn arithmetic(shell: &mut Shell, expr: &str) -> Result<i64, String> {
+    let expr = expand_string(expr, shell)?;
+    let lookup = |name: &str| parameter_value(shell, name);
+    crate::arith::evaluate(&expr, &lookup)
+}
+
+/// The field separators: `$IFS`, or space, tab and newline when it is unset.
+pub fn ifs(shell: &Shell) -> &str {
+    shell.vars.get("IFS").unwrap_or(" \t\n")
+}
+
+/// Collects the words produced by expanding a command line. A word exists
+/// once it has any text or any quotes in it, so `""` is an empty argument
+/// while an unquoted expansion that comes out empty disappears.
+///
+/// With pathname expansion on, each word is also kept as a pattern in which
+/// quoted characters are escaped; a word with unquoted `*`, `?` or `[` is
+/// replaced by the paths it matches, if there are any.
+pub struct Words {
+    words: Vec<String>,
+    current: String,
+    exists: bool,
+    glob: bool,
+    pattern: String,
+    has_glob: bool,
+}
+
+impl Words {
+    pub fn new(glob: bool) -> Self {
+        Words {
+            words: Vec::new(),
+            current: String::new(),
+            exists: false,
+            glob,
+            pattern: String::new(),
+            has_glob: false,
+        }
+    }
+
+    /// Appends a quoted or escaped character, which only matches itself.
+    pub fn push(&mut self, ch: char) {
+        self.current.push(ch);
+        if self.glob {
+            self.pattern.push_str(&pattern::escape(&ch.to_string()));
+        }
+        self.exists = true;
+    }
+
+    pub fn push_str(&mut self, text: &str) {
+        self.current.push_str(text);
+        if self.glob {
+            self.pattern.push_str(&pattern::escape(text));
+        }
+        self.exists = true;
+    }
+
+    /// Appends an unquoted character, which keeps its pattern meaning.
+    pub fn push_unquoted(&mut self, ch: char) {
+        self.current.push(ch);
+        if self.glob {
+            self.pattern.push(ch);
+            self.has_glob |= "*?[".contains(ch);
+        }
+        self.exists = true;
+    }
+
+    /// The text of the word being built.
+    pub fn current(&self) -> &str {
+        &self.current
+    }
+
+    /// Makes the current word exist even if it stays empty.
+    pub fn mark(&mut self) {
+        self.exists = true;
+    }
+
+    /// Ends the current word, if there is one.
+    pub fn finish(&mut self) {
+        let pattern = std::mem::take(&mut self.pattern);
+        let has_glob = std::mem::take(&mut self.has_glob);
+        if !self.exists {
+            return;
+        }
+        self.exists = false;
+        let current = std::mem::take(&mut self.current);
+        let paths = if has_glob { pattern::expand_path(&pattern) } else { Vec::new() };
+        if paths.is_empty() {
+            self.words.push(current);
+        } else {
+            self.words.extend(paths);
+        }
+    }
+
+    /// Appends the result of an unquoted expansion, splitting it into fields
+    /// at the characters in `ifs`. Runs of IFS whitespace count as a single
+    /// delimiter and are dropped at the ends; every other IFS character ends
+    /// a field, so `a::b` with `IFS=:` gives `a`, an empty field and `b`.
+    pub fn push_split(&mut self, value: &str, ifs: &str) {
+        // Whether the field was just ended by whitespace, which then merges
+        // with a following non-whitespace delimiter.
+        let mut ended_by_whitespace = false;
+        for ch in value.chars() {
+            if !ifs.contains(ch) {
+                self.push_unquoted(ch);
+                ended_by_whitespace = false;
+            } else if ch.is_whitespace() {
+                if self.exists {
+                    self.finish();
+                    ended_by_whitespace = true;
+                }
+            } else {
+                if self.exists || !ended_by_whitespace {
+                    self.exists = true;
+                    self.finish();
+                }
+                ended_by_whitespace = false;
+            }
+        }
+    }
+
+    pub fn into_words(mut self) -> Vec<String> {
+        self.finish();
+        self.words
+    }
+}
+
+/// The value of a scalar parameter: a special parameter, a positional
+/// parameter, a dynamic variable such as `RANDOM`, or a shell variable.
+pub fn parameter_value(shell: &Shell, name: &str) -> Option<String> {
+    match name {
+        "#" => Some(shell.positional.len().to_string()),
+        "@" | "*" => Some(shell.positional.join(" ")),
+        "?" => Some(shell.last_status.to_string()),
+        "$" => Some(shell.pid.to_string()),
+        "!" => shell.last_background.map(|pid| pid.to_string()),
+        "-" => Some(shell.option_flags()),
+        "0" => Some(shell.name.clone()),
+        "_" => Some(shell.last_argument.clone()),
+        _ if name.starts_with(|c: char| c.is_ascii_digit()) => name
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| shell.positional.get(n.wrapping_sub(1)))
+            .cloned(),
+        _ if shell.vars.lookup(name).is_none() => None,
+        _ => shell
+            .dynamic
+            .value(name, shell.lineno)
+            .or_else(|| shell.vars.get(name).map(str::to_string)),
+    }
+}
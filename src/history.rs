@@ -0,0 +1,507 @@
+//! The command history: the lines entered at the prompt, which both the
+//! line editor's Up-arrow recall and the `history` builtin read from, kept
+//! between sessions in `$HISTFILE`. References such as `!!` in a line
+//! typed at the prompt are expanded from it before the line is parsed.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+
+#[derive(Default)]
+pub struct History {
+    entries: Vec<String>,
+    /// How many entries have been dropped from the front, so each entry
+    /// keeps its number as older ones go.
+    base: usize,
+    /// Bumped on every change, so the line editor's copy is only rebuilt
+    /// when there is something new.
+    revision: u64,
+    /// The index of the first entry not yet written to the history file,
+    /// which `history -a` appends from.
+    unsaved: usize,
+    /// How many lines of the history file have been read or written, so
+    /// `history -n` reads only the lines added since.
+    file_lines: usize,
+    /// The last `:s/old/new/` of a history expansion, which `:&` repeats.
+    substitution: Option<(String, String)>,
+}
+
+impl History {
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// The number `history` shows for the entry at `index`.
+    pub fn number(&self, index: usize) -> usize {
+        self.base + index + 1
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Adds `line`, dropping the oldest entries beyond `limit`.
+    pub fn push(&mut self, line: &str, limit: Option<usize>) {
+        self.entries.push(line.to_string());
+        self.revision += 1;
+        self.truncate(limit);
+    }
+
+    /// Drops the oldest entries beyond `limit`.
+    pub fn truncate(&mut self, limit: Option<usize>) {
+        let excess = limit.map_or(0, |limit| self.entries.len().saturating_sub(limit));
+        if excess > 0 {
+            self.delete(0..excess);
+            self.base += excess;
+        }
+    }
+
+    /// Removes the entries at `range`; later entries take their numbers.
+    pub fn delete(&mut self, range: Range<usize>) {
+        self.unsaved -= (range.start.min(self.unsaved)..range.end.min(self.unsaved)).len();
+        self.entries.drain(range);
+        self.revision += 1;
+    }
+
+    /// Removes the newest entry: the `history` command that is running, when
+    /// it came from the prompt.
+    pub fn pop(&mut self) {
+        if !self.entries.is_empty() {
+            self.delete(self.entries.len() - 1..self.entries.len());
+        }
+    }
+
+    /// Empties the history, so numbering starts again from 1.
+    pub fn clear(&mut self) {
+        self.delete(0..self.entries.len());
+        self.base = 0;
+    }
+
+    /// The index of the entry `history -d` names by `offset`: its number, or
+    /// counting back from the newest entry when negative.
+    pub fn index(&self, offset: i64) -> Option<usize> {
+        let index = if offset < 0 {
+            self.entries.len().checked_sub(offset.unsigned_abs() as usize)?
+        } else {
+            (offset as usize).checked_sub(self.base + 1)?
+        };
+        (index < self.entries.len()).then_some(index)
+    }
+
+    /// Appends the lines of the history file at `path`, then keeps the
+    /// newest `limit` entries. The lines read count as saved.
+    pub fn load(&mut self, path: &Path, limit: Option<usize>) -> io::Result<()> {
+        let lines = parse(&fs::read(path)?);
+        self.file_lines = lines.len();
+        self.extend(lines, limit);
+        Ok(())
+    }
+
+    /// Appends the lines added to the history file at `path` since it was
+    /// last read or written, for `history -n`.
+    pub fn load_new(&mut self, path: &Path, limit: Option<usize>) -> io::Result<()> {
+        let mut lines = parse(&fs::read(path)?);
+        let read = self.file_lines.min(lines.len());
+        self.file_lines = lines.len();
+        self.extend(lines.split_off(read), limit);
+        Ok(())
+    }
+
+    fn extend(&mut self, lines: Vec<String>, limit: Option<usize>) {
+        self.entries.extend(lines);
+        self.unsaved = self.entries.len();
+        self.revision += 1;
+        self.truncate(limit);
+    }
+
+    /// Writes the history to `path`, keeping only the newest `file_limit`
+    /// lines.
+    pub fn save(&mut self, path: &Path, file_limit: Option<usize>) -> io::Result<()> {
+        let skip = file_limit.map_or(0, |limit| self.entries.len().saturating_sub(limit));
+        fs::write(path, lines(&self.entries[skip..]))?;
+        self.unsaved = self.entries.len();
+        self.file_lines = self.entries.len() - skip;
+        Ok(())
+    }
+
+    /// Appends the entries not yet written to the history file at `path`,
+    /// for `history -a`.
+    pub fn append_new(&mut self, path: &Path) -> io::Result<()> {
+        let new = &self.entries[self.unsaved..];
+        OpenOptions::new().append(true).create(true).open(path)?.write_all(lines(new).as_bytes())?;
+        self.file_lines += new.len();
+        self.unsaved = self.entries.len();
+        Ok(())
+    }
+}
+
+/// The contents of a history file holding `entries`.
+fn lines(entries: &[String]) -> String {
+    let mut contents = String::new();
+    for entry in entries {
+        contents.push_str(entry);
+        contents.push('\n');
+    }
+    contents
+}
+
+/// The entries in a history file. Bytes that aren't UTF-8 are replaced, and
+/// empty lines, lines holding a NUL and bash's `#<seconds>` timestamp lines
+/// are skipped, so a damaged file still loads what it can.
+fn parse(contents: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(contents)
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.contains('\0') && !is_timestamp(line))
+        .map(str::to_string)
+        .collect()
+}
+
+fn is_timestamp(line: &str) -> bool {
+    line.strip_prefix('#')
+        .is_some_and(|seconds| !seconds.is_empty() && seconds.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+/// The limit set by `$HISTSIZE` or `$HISTFILESIZE`: a non-negative number,
+/// or none when the variable is unset, empty, negative or not a number.
+pub fn limit(value: Option<&str>) -> Option<usize> {
+    value?.trim().parse().ok()
+}
+
+/// A line after history expansion.
+pub struct Expansion {
+    pub line: String,
+    /// Whether the line held any history references, in which case the
+    /// shell shows what it expanded to.
+    pub changed: bool,
+    /// Set by the `:p` modifier: the line is shown and saved, not run.
+    pub print_only: bool,
+}
+
+impl History {
+    /// Replaces the csh-style history references in `line`: an event such as
+    /// `!!`, `!n`, `!-n`, `!string`, `!?string?` or `!#`, optionally followed
+    /// by a word designator and `:`-separated modifiers, and a leading
+    /// `^old^new`. Nothing is expanded inside single quotes or after a
+    /// backslash. The error is the message to show, without a prefix.
+    pub fn expand(&mut self, line: &str) -> Result<Expansion, String> {
+        // `^old^new^` is short for `!!:s^old^new^`.
+        let quick;
+        let line = if line.starts_with('^') {
+            quick = format!("!!:s{}", line);
+            quick.as_str()
+        } else {
+            line
+        };
+
+        let chars: Vec<char> = line.chars().collect();
+        let mut expansion = Expansion { line: String::new(), changed: false, print_only: false };
+        let (mut single, mut double) = (false, false);
+        let mut i = 0;
+        while i < chars.len() {
+            let ch = chars[i];
+            match ch {
+                '\\' if !single => {
+                    expansion.line.extend(&chars[i..(i + 2).min(chars.len())]);
+                    i += 2;
+                    continue;
+                }
+                '\'' if !double => single = !single,
+                '"' if !single => double = !double,
+                '!' if !single => {
+                    let next = chars.get(i + 1).copied();
+                    let literal = next
+                        .map_or(true, |next| next.is_whitespace() || next == '=' || next == '(' || (double && next == '"'));
+                    if !literal {
+                        let (text, end) = self.reference(&chars, i, &expansion.line, &mut expansion.print_only)?;
+                        expansion.line.push_str(&text);
+                        expansion.changed = true;
+                        i = end;
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            expansion.line.push(ch);
+            i += 1;
+        }
+        Ok(expansion)
+    }
+
+    /// Expands the reference starting with the `!` at `chars[start]`, given
+    /// the line expanded so far for `!#`. Returns the text and where the
+    /// reference ends.
+    fn reference(
+        &mut self,
+        chars: &[char],
+        start: usize,
+        so_far: &str,
+        print_only: &mut bool,
+    ) -> Result<(String, usize), String> {
+        let mut i = start + 1;
+        let text = |from: usize, to: usize| chars[from..to].iter().collect::<String>();
+        let event = match chars[i] {
+            '!' => {
+                i += 1;
+                self.entries.last().cloned()
+            }
+            '#' => {
+                i += 1;
+                Some(so_far.to_string())
+            }
+            ':' | '^' | '$' | '*' => self.entries.last().cloned(),
+            ch if ch.is_ascii_digit() || (ch == '-' && chars.get(i + 1).is_some_and(char::is_ascii_digit)) => {
+                let digits = i + 1 + chars[i + 1..].iter().take_while(|ch| ch.is_ascii_digit()).count();
+                let n: i64 = text(i, digits).parse().unwrap_or(0);
+                i = digits;
+                let index = if n < 0 {
+                    self.entries.len().checked_sub(n.unsigned_abs() as usize).filter(|_| n != 0)
+                } else {
+                    (n as usize).checked_sub(self.base + 1).filter(|&index| index < self.entries.len())
+                };
+                index.map(|index| self.entries[index].clone())
+            }
+            '?' => {
+                let end = chars[i + 1..].iter().position(|&ch| ch == '?').map_or(chars.len(), |pos| i + 1 + pos);
+                let needle = text(i + 1, end);
+                i = (end + 1).min(chars.len());
+                self.entries.iter().rev().find(|entry| entry.contains(&needle)).cloned()
+            }
+            _ => {
+                let end = i + chars[i..].iter().take_while(|&&ch| !ends_event(ch)).count();
+                let prefix = text(i, end);
+                i = end;
+                self.entries.iter().rev().find(|entry| entry.starts_with(&prefix)).cloned()
+            }
+        };
+        let Some(event) = event else {
+            return Err(format!("{}: event not found", text(start, i)));
+        };
+
+        // A word designator, where the `:` may be left out before `^`, `$`
+        // and `*`.
+        let mut result = event.clone();
+        let colon = chars.get(i) == Some(&':') && chars.get(i + 1).is_some_and(|&ch| starts_designator(ch));
+        if colon || chars.get(i).is_some_and(|&ch| matches!(ch, '^' | '$' | '*')) {
+            let from = i;
+            i += usize::from(colon);
+            let words = split_words(&event);
+            let (range, end) = designator(chars, i, words.len());
+            i = end;
+            result = match range {
+                Some(range) => words[range].join(" "),
+                None => return Err(format!("{}: bad word specifier", text(from, i))),
+            };
+        }
+
+        while chars.get(i) == Some(&':') && chars.get(i + 1).is_some_and(|ch| !ch.is_whitespace()) {
+            let from = i;
+            i += 1;
+            let mut global = false;
+            if chars[i] == 'g' || chars[i] == 'a' {
+                global = true;
+                i += 1;
+            }
+            match chars.get(i).copied() {
+                Some('h') if !global => {
+                    if let Some(slash) = result.rfind('/') {
+                        result.truncate(slash);
+                    }
+                }
+                Some('t') if !global => {
+                    if let Some(slash) = result.rfind('/') {
+                        result.drain(..=slash);
+                    }
+                }
+                Some('r') if !global => {
+                    if let Some(dot) = suffix(&result) {
+                        result.truncate(dot);
+                    }
+                }
+                Some('e') if !global => {
+                    result = suffix(&result).map_or_else(String::new, |dot| result[dot..].to_string());
+                }
+                Some('p') if !global => *print_only = true,
+                Some('q') if !global => result = quote(&result),
+                Some('x') if !global => {
+                    result = split_words(&result).iter().map(|word| quote(word)).collect::<Vec<_>>().join(" ");
+                }
+                Some('s') | Some('&') => {
+                    if chars[i] == 's' {
+                        let end = self.parse_substitution(chars, i + 1);
+                        i = end - 1;
+                    }
+                    let substituted = self.substitution.as_ref();
+                    match substituted.and_then(|(old, new)| substitute(&result, old, new, global)) {
+                        Some(substituted) => result = substituted,
+                        None => return Err(format!("{}: substitution failed", text(from, i + 1))),
+                    }
+                }
+                _ => {
+                    let modifier = chars.get(i).map_or(String::new(), |ch| ch.to_string());
+                    return Err(format!("{}: unrecognized history modifier", modifier));
+                }
+            }
+            i += 1;
+        }
+        Ok((result, i))
+    }
+
+    /// Reads `/old/new/` at `chars[start]`, where any character may stand
+    /// for `/`, into the remembered substitution. An empty `old` keeps the
+    /// previous one, `&` in `new` stands for `old`, and the last delimiter
+    /// may be left off at the end of the line. Returns where it ends.
+    fn parse_substitution(&mut self, chars: &[char], start: usize) -> usize {
+        let Some(&delimiter) = chars.get(start) else {
+            return start;
+        };
+        let mut i = start + 1;
+        let part = |i: &mut usize| {
+            let mut text = String::new();
+            while let Some(&ch) = chars.get(*i) {
+                *i += 1;
+                if ch == delimiter {
+                    break;
+                }
+                if ch == '\\' && chars.get(*i) == Some(&delimiter) {
+                    text.push(delimiter);
+                    *i += 1;
+                } else {
+                    text.push(ch);
+                }
+            }
+            text
+        };
+        let old = part(&mut i);
+        let new = part(&mut i);
+        let old = match (old.is_empty(), &self.substitution) {
+            (true, Some((previous, _))) => previous.clone(),
+            _ => old,
+        };
+        let new = new.replace('&', &old);
+        self.substitution = Some((old, new));
+        i
+    }
+}
+
+/// Whether `ch` ends the string of a `!string` event.
+fn ends_event(ch: char) -> bool {
+    ch.is_whitespace() || matches!(ch, ':' | ';' | '&' | '|' | '<' | '>' | '(' | ')' | '"' | '\'' | '`')
+}
+
+fn starts_designator(ch: char) -> bool {
+    ch.is_ascii_digit() || matches!(ch, '^' | '$' | '*' | '-')
+}
+
+/// Parses the word designator at `chars[start]` for an event of `count`
+/// words: `n`, `^`, `$`, `x-y`, `x-`, `-y`, `x*` or `*`. Returns the words it
+/// selects, or `None` if they are out of range, and where it ends.
+fn designator(chars: &[char], start: usize, count: usize) -> (Option<Range<usize>>, usize) {
+    let last = count.saturating_sub(1);
+    let word = |i: &mut usize| -> Option<usize> {
+        match chars.get(*i) {
+            Some('^') => {
+                *i += 1;
+                Some(1)
+            }
+            Some('$') => {
+                *i += 1;
+                Some(last)
+            }
+            Some(ch) if ch.is_ascii_digit() => {
+                let digits = chars[*i..].iter().take_while(|ch| ch.is_ascii_digit()).count();
+                let n = chars[*i..*i + digits].iter().collect::<String>().parse().ok();
+                *i += digits;
+                n
+            }
+            _ => None,
+        }
+    };
+
+    let mut i = start;
+    if chars.get(i) == Some(&'*') {
+        // Every argument, which may be none at all.
+        return (Some(1.min(count)..count), i + 1);
+    }
+    let first = if chars.get(i) == Some(&'-') { Some(0) } else { word(&mut i) };
+    let end = match chars.get(i) {
+        Some('*') => {
+            i += 1;
+            // `x*` past the last word selects nothing.
+            if first == Some(count) {
+                return (Some(count..count), i);
+            }
+            Some(last)
+        }
+        // `x-` stops short of the last word.
+        Some('-') => {
+            i += 1;
+            word(&mut i).or(last.checked_sub(1))
+        }
+        _ => first,
+    };
+    let range = match (first, end) {
+        (Some(first), Some(end)) if first <= end && end < count => Some(first..end + 1),
+        _ => None,
+    };
+    (range, i)
+}
+
+/// Splits a history entry into words as a word designator counts them:
+/// quoted text stays in one word and the shell's operators are words of
+/// their own.
+fn split_words(line: &str) -> Vec<String> {
+    let chars: Vec<char> = line.chars().collect();
+    let operator = |ch: char| matches!(ch, ';' | '&' | '|' | '<' | '>' | '(' | ')');
+    let mut words = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i].is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        if operator(chars[i]) {
+            let doubled = chars.get(i + 1).is_some_and(|&next| {
+                (next == chars[i] && !matches!(next, '(' | ')')) || (matches!(chars[i], '<' | '>') && next == '&')
+            });
+            i += if doubled { 2 } else { 1 };
+        } else {
+            let mut quote = None;
+            while i < chars.len() {
+                let ch = chars[i];
+                match quote {
+                    Some(q) if ch == q => quote = None,
+                    Some('"') if ch == '\\' => i += 1,
+                    Some(_) => {}
+                    None if ch == '\'' || ch == '"' => quote = Some(ch),
+                    None if ch == '\\' => i += 1,
+                    None if ch.is_whitespace() || operator(ch) => break,
+                    None => {}
+                }
+                i += 1;
+            }
+        }
+        words.push(chars[start..i.min(chars.len())].iter().collect());
+    }
+    words
+}
+
+/// Where the `.suffix` of the last path component in `text` starts.
+fn suffix(text: &str) -> Option<usize> {
+    let dot = text.rfind('.')?;
+    (dot > text.rfind('/').map_or(0, |slash| slash + 1) || (dot == 0 && text.len() > 1)).then_some(dot)
+}
+
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+/// Replaces the first occurrence of `old` in `text` with `new`, or every
+/// one when `global`. Returns `None` if there is none.
+fn substitute(text: &str, old: &str, new: &str, global: bool) -> Option<String> {
+    if old.is_empty() || !text.contains(old) {
+        return None;
+    }
+    Some(if global { text.replace(old, new) } else { text.replacen(old, new, 1) })
+}
@@ -0,0 +1,160 @@
+//! What a command name refers to: a reserved word, a builtin, or an
+//! executable file in `PATH`. Running commands, `type`, `command -v` and tab
+//! completion all look names up here, so they agree. RustyShell has no
+//! aliases or shell functions, so a name never resolves to either.
+//!
+//! Where commands were found is remembered in a [`HashTable`], so running
+//! one again doesn't search every `PATH` directory.
+
+use std::collections::BTreeMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// The reserved words the parser recognizes.
+pub const KEYWORDS: [&str; 3] = ["!", "[[", "]]"];
+
+/// Every builtin, as `type` and completion list them.
+pub const BUILTINS: [&str; 27] = [
+    ".", "[", "builtin", "cd", "command", "declare", "dirs", "echo", "exit", "export", "hash", "history", "popd",
+    "printf", "pushd", "pwd", "read", "readonly", "return", "set", "source", "test", "trap", "type", "typeset",
+    "unset", "wait",
+];
+
+/// One meaning of a command name.
+pub enum Command {
+    Keyword,
+    Builtin,
+    File(PathBuf),
+}
+
+pub fn is_builtin(name: &str) -> bool {
+    BUILTINS.contains(&name)
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.is_file() && path.metadata().is_ok_and(|m| m.permissions().mode() & 0o111 != 0)
+}
+
+/// Every executable named `name` in the `PATH` directories, in order.
+fn find_all_in_path(name: &str, path_var: Option<&str>) -> Vec<PathBuf> {
+    if name.contains('/') {
+        let path = PathBuf::from(name);
+        return if is_executable(&path) { vec![path] } else { Vec::new() };
+    }
+    let Some(path_var) = path_var else {
+        return Vec::new();
+    };
+    path_var
+        .split(':')
+        .map(|dir| Path::new(dir).join(name))
+        .filter(|full_path| is_executable(full_path))
+        .collect()
+}
+
+/// The meanings of `name` in the order the shell tries them, or only the
+/// first unless `all`.
+pub fn resolve(name: &str, path_var: Option<&str>, all: bool) -> Vec<Command> {
+    let mut found = Vec::new();
+    if KEYWORDS.contains(&name) {
+        found.push(Command::Keyword);
+    }
+    if is_builtin(name) {
+        found.push(Command::Builtin);
+    }
+    if !all && !found.is_empty() {
+        return found;
+    }
+    let files = find_all_in_path(name, path_var).into_iter().map(Command::File);
+    if all {
+        found.extend(files);
+    } else {
+        found.extend(files.take(1));
+    }
+    found
+}
+
+/// A remembered location and how many times it has been used.
+pub struct Hashed {
+    pub path: PathBuf,
+    pub hits: usize,
+}
+
+/// The commands found in `PATH` so far, by name. The table is emptied when
+/// `PATH` changes, and an entry whose file has gone is looked up afresh.
+#[derive(Default)]
+pub struct HashTable {
+    /// The `PATH` the entries were found in.
+    path: Option<String>,
+    entries: BTreeMap<String, Hashed>,
+}
+
+impl HashTable {
+    /// Forgets every entry if `PATH` is no longer what they were found in.
+    pub fn sync(&mut self, path_var: Option<&str>) {
+        if self.path.as_deref() != path_var {
+            self.entries.clear();
+            self.path = path_var.map(str::to_string);
+        }
+    }
+
+    /// Resolves a command name the way `execvp` does: names containing a
+    /// `/` are used as-is, anything else comes from the table or else is
+    /// looked up in each `PATH` directory and remembered.
+    pub fn find(&mut self, name: &str, path_var: Option<&str>) -> Option<PathBuf> {
+        if name.contains('/') {
+            return Some(PathBuf::from(name));
+        }
+        self.sync(path_var);
+        match self.entries.get_mut(name) {
+            Some(hashed) if is_executable(&hashed.path) => {
+                hashed.hits += 1;
+                return Some(hashed.path.clone());
+            }
+            Some(_) => {
+                self.entries.remove(name);
+            }
+            None => {}
+        }
+        let path = find_all_in_path(name, path_var).into_iter().next()?;
+        self.entries.insert(name.to_string(), Hashed { path: path.clone(), hits: 1 });
+        Some(path)
+    }
+
+    /// Looks `name` up in `PATH` and remembers it without counting a use,
+    /// for `hash name`. Returns whether it was found.
+    pub fn add(&mut self, name: &str, path_var: Option<&str>) -> bool {
+        self.sync(path_var);
+        match find_all_in_path(name, path_var).into_iter().next() {
+            Some(path) => {
+                self.insert(name, path);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remembers `path` for `name`, as `hash -p` does.
+    pub fn insert(&mut self, name: &str, path: PathBuf) {
+        self.entries.insert(name.to_string(), Hashed { path, hits: 0 });
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Hashed> {
+        self.entries.get(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.entries.remove(name).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Hashed)> {
+        self.entries.iter().map(|(name, hashed)| (name.as_str(), hashed))
+    }
+}
@@ -1,11 +1,12 @@
-use std::io::Write;
+use std::io::{Read, Write};
 //use std::io::{self, Write};
 use std::env;
 use std::fs;
-use std::process::{self, Command, Stdio};
-use std::path::Path;
-use std::os::unix::fs::PermissionsExt;
-use std::os::unix::process::CommandExt;
+use std::process::{self, Command, ExitStatus, Stdio};
+use std::path::{Path, PathBuf};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::fs::{File, OpenOptions};
 
 use rustyline::completion::{Completer, Pair};
@@ -14,18 +15,139 @@ use rustyline::{Editor, Helper, Context, Config, CompletionType};
 use rustyline::highlight::Highlighter;
 use rustyline::hint::Hinter;
 use rustyline::validate::{Validator, ValidationResult, ValidationContext};
-use rustyline::history::FileHistory;
+use rustyline::history::{FileHistory, History as _};
 
-use nix::sys::wait::waitpid;
-use nix::unistd::{close, execvp, fork, pipe, ForkResult};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{close, execve, fork, pipe, ForkResult, Pid};
 //use nix::unistd::dup2;
 use std::ffi::CString;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::time::Duration;
 //use std::os::unix::io::RawFd;
-use std::os::fd::IntoRawFd;
-use libc;
 use std::os::unix::io::AsRawFd;
 
-struct ShellCompleter;
+mod arith;
+mod cond;
+mod dirs;
+mod dynamic;
+mod expand;
+mod history;
+mod lookup;
+mod options;
+mod parse;
+mod pattern;
+mod printf;
+mod read;
+mod startup;
+mod trap;
+mod vars;
+mod xtrace;
+
+use dirs::{abbreviate, logical_path, search_cdpath, DirStack, Offset};
+use dynamic::DynamicVars;
+use expand::{expand_parameter, ifs, Expansion, Words, UNBOUND};
+use history::History;
+use lookup::{is_builtin, HashTable, BUILTINS};
+use options::{Options, OPTIONS};
+use parse::{parse_list, AndOr, Connector, Pipeline};
+use startup::{init_variables, load_startup_files, StartupOptions};
+use printf::Escape;
+use read::{End, ReadOptions};
+use trap::{Condition, Traps};
+use vars::{assignment, declaration, is_valid_name, Variables};
+
+/// State that lives for the whole session and is shared by every command run
+/// in the current execution context (interactive lines and sourced files).
+struct Shell {
+    history: History,
+    vars: Variables,
+    positional: Vec<String>,
+    last_status: i32,
+    source_depth: usize,
+    /// The `source_depth` that top-level commands run at: 1 while running a
+    /// script, which is read like a sourced file, and 0 otherwise.
+    base_depth: usize,
+    returning: Option<i32>,
+    /// `$0`: the script being run, or the shell's own name.
+    name: String,
+    /// `$$`: the pid of the shell itself, unchanged in forked children.
+    pid: u32,
+    /// `$!`: the pid of the most recent background job, once there is one.
+    last_background: Option<i32>,
+    /// Background jobs that `wait` has not collected yet.
+    jobs: Vec<Pid>,
+    /// `$_`: the last argument of the previous command.
+    last_argument: String,
+    interactive: bool,
+    /// The line of the current script, or the number of lines read so far
+    /// when interactive; `LINENO` expands to it.
+    lineno: usize,
+    /// The script or sourced file being run, named in syntax errors.
+    file: Option<String>,
+    /// How many lines have failed to parse, so `set -n` can report failure.
+    syntax_errors: usize,
+    /// Commands come from a non-terminal stdin through the standard
+    /// library's buffer, which `read` must then share.
+    input_buffered: bool,
+    dynamic: DynamicVars,
+    options: Options,
+    traps: Traps,
+    dir_stack: DirStack,
+    hash: HashTable,
+}
+
+impl Shell {
+    fn new() -> Self {
+        let mut vars = Variables::from_env();
+        for name in dynamic::NAMES {
+            let _ = vars.set(name, "");
+        }
+        Shell {
+            history: History::default(),
+            vars,
+            positional: Vec::new(),
+            last_status: 0,
+            source_depth: 0,
+            base_depth: 0,
+            returning: None,
+            name: "rustyshell".to_string(),
+            pid: process::id(),
+            last_background: None,
+            jobs: Vec::new(),
+            last_argument: String::new(),
+            interactive: false,
+            lineno: 0,
+            file: None,
+            syntax_errors: 0,
+            input_buffered: false,
+            dynamic: DynamicVars::new(),
+            options: Options::default(),
+            traps: Traps::default(),
+            dir_stack: DirStack::default(),
+            hash: HashTable::default(),
+        }
+    }
+
+    /// How many `source` commands deep the current command is.
+    fn nesting(&self) -> usize {
+        self.source_depth.saturating_sub(self.base_depth)
+    }
+
+    /// `$-`: the letters of the options in effect.
+    fn option_flags(&self) -> String {
+        let mut flags = self.options.letters();
+        if self.interactive {
+            flags.push('i');
+        }
+        flags
+    }
+}
+
+struct ShellCompleter {
+    /// The shell's `PATH`, refreshed before every prompt.
+    path: Option<String>,
+}
 
 impl Hinter for ShellCompleter {
     type Hint = String;
@@ -55,8 +177,7 @@ impl Completer for ShellCompleter {
         let mut completions = vec![];
 
 
-        let builtins = ["exit", "echo", "cd", "pwd", "type"];
-        for &builtin in &builtins {
+        for &builtin in &BUILTINS {
             if builtin.starts_with(prefix) {
                 completions.push(Pair {
                     display: builtin.to_string(),
@@ -65,7 +186,7 @@ impl Completer for ShellCompleter {
             }
         }
 
-        if let Ok(paths) = env::var("PATH") {
+        if let Some(paths) = &self.path {
             for dir in paths.split(':') {
                 if let Ok(entries) = fs::read_dir(dir) {
                     for entry in entries.flatten() {
@@ -91,7 +212,7 @@ impl Completer for ShellCompleter {
 
         // Calculate the start of the word to replace
         let start = line[..pos]
-            .rfind(|c: char| c == ' ' || c == '\t')
+            .rfind([' ', '\t'])
             .map_or(0, |i| i + 1);
 
         Ok((start, completions))
@@ -99,360 +220,825 @@ impl Completer for ShellCompleter {
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let argv: Vec<String> = env::args().collect();
+    let options = match StartupOptions::parse(&argv) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("rustyshell: {}", e);
+            eprintln!(
+                "usage: rustyshell [-il] [-aefnuvx] [--check] [--norc] [--noprofile] [--rcfile file] [script [args...]]"
+            );
+            process::exit(2);
+        }
+    };
+
+    let mut shell = Shell::new();
+    shell.interactive = options.interactive;
+    shell.options.histexpand = options.interactive;
+    if let Some(script) = &options.script {
+        shell.name = script.clone();
+    } else if let Some(arg0) = argv.first() {
+        shell.name = arg0.clone();
+    }
+    init_variables(&mut shell);
+    load_startup_files(&mut shell, &options);
+    for name in &options.set {
+        shell.options.set(name, true);
+    }
+    if shell.interactive {
+        load_history(&mut shell);
+    }
+
+    if let Some(script) = &options.script {
+        let path = Path::new(script);
+        if !path.is_file() {
+            eprintln!("rustyshell: {}: No such file or directory", script);
+            process::exit(127);
+        }
+        shell.positional = options.script_args.clone();
+        shell.base_depth = 1;
+        let status = source_file(&mut shell, path, &[]);
+        let status = exit_status(&shell, status);
+        exit_shell(&mut shell, status);
+    }
 
+    shell.input_buffered = !shell.interactive;
     let config = Config::builder()
         .completion_type(CompletionType::List)
         .build();
 
     let mut rl = Editor::<_, FileHistory>::with_config(config).unwrap();    
-    let completer = ShellCompleter;
+    let completer = ShellCompleter { path: None };
     rl.set_helper(Some(completer));
-
-    let mut history: Vec<String> = Vec::new();
+    let mut synced_history = None;
 
     loop {
+        run_pending_traps(&mut shell);
+        if let Some(helper) = rl.helper_mut() {
+            helper.path = shell.vars.get("PATH").map(str::to_string);
+        }
+        if synced_history != Some(shell.history.revision()) {
+            sync_editor_history(&mut rl, &shell.history);
+            synced_history = Some(shell.history.revision());
+        }
         let readline = rl.readline("$ ");
         let input = match readline {
             Ok(line) => {
                 if line.trim().is_empty() {
                     continue;
                 }
-                let _ = rl.add_history_entry(line.as_str());
                 line
             }
-            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break Ok(()),
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+                let status = exit_status(&shell, shell.last_status);
+                exit_shell(&mut shell, status);
+            }
             Err(_) => continue,
         };
 
-        //let _ = rl.add_history_entry(line.as_str());
-
-        let trimmed = input.trim();
-        let parts = tokenize(trimmed);
-        if parts.is_empty() {
-            continue;
+        let mut line = input.trim().to_string();
+        let mut print_only = false;
+        if shell.options.histexpand {
+            // A line with a reference that can't be expanded is neither run
+            // nor remembered.
+            match shell.history.expand(&line) {
+                Ok(expansion) => {
+                    if expansion.changed {
+                        println!("{}", expansion.line);
+                    }
+                    line = expansion.line;
+                    print_only = expansion.print_only;
+                }
+                Err(e) => {
+                    eprintln!("rustyshell: {}", e);
+                    continue;
+                }
+            }
+        }
+        let limit = history::limit(shell.vars.get("HISTSIZE"));
+        shell.history.push(&line, limit);
+        shell.lineno += 1;
+        if !print_only {
+            run_line(&mut shell, &line);
         }
+    }
+}
 
-        let command = &parts[0];
-        let args = &parts[1..];
+/// Replaces the line editor's history, which Up-arrow recalls, with the
+/// shell's own.
+fn sync_editor_history(rl: &mut Editor<ShellCompleter, FileHistory>, history: &History) {
+    let editor = rl.history_mut();
+    let _ = editor.clear();
+    let _ = editor.ignore_dups(false);
+    let _ = editor.set_max_len(history.entries().len());
+    for entry in history.entries() {
+        let _ = editor.add(entry);
+    }
+}
 
-        if trimmed == "exit 0" {
-            process::exit(0);
-        }
+/// The history file, `$HISTFILE`, unless it is unset or empty.
+fn history_file(shell: &Shell) -> Option<PathBuf> {
+    shell.vars.get("HISTFILE").filter(|path| !path.is_empty()).map(PathBuf::from)
+}
 
-        if trimmed.contains('|') {
-            handle_pipeline(trimmed);
-            continue;
+/// Reads the history file into the history of an interactive shell. A
+/// missing file is not an error; one that can't be read gets a warning.
+fn load_history(shell: &mut Shell) {
+    let Some(path) = history_file(shell) else {
+        return;
+    };
+    let limit = history::limit(shell.vars.get("HISTSIZE"));
+    if let Err(e) = shell.history.load(&path, limit) {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            eprintln!("rustyshell: {}: {}", path.display(), os_error(&e));
         }
+    }
+}
 
+/// Writes the history to the history file as an interactive shell exits,
+/// trimmed to `$HISTFILESIZE` lines, warning if the file can't be written.
+fn save_history(shell: &mut Shell) {
+    let Some(path) = history_file(shell) else {
+        return;
+    };
+    let limit = history::limit(shell.vars.get("HISTFILESIZE"));
+    if let Err(e) = shell.history.save(&path, limit) {
+        eprintln!("rustyshell: {}: {}", path.display(), os_error(&e));
+    }
+}
 
-        history.push(trimmed.to_string());
+/// The status the shell exits with once its input runs out: under `set -n`
+/// that is 2 if any line failed to parse, and `status` otherwise.
+fn exit_status(shell: &Shell, status: i32) -> i32 {
+    if shell.options.noexec && shell.syntax_errors > 0 {
+        2
+    } else {
+        status
+    }
+}
 
-        if command == "history" {
-            match args.len() {
-                0 => {
+/// Leaves the shell with `status`, running the `EXIT` trap first. An `exit`
+/// inside the trap replaces the status.
+fn exit_shell(shell: &mut Shell, status: i32) -> ! {
+    shell.last_status = status;
+    if let Some(action) = shell.traps.take(Condition::Exit) {
+        run_trap(shell, &action);
+    }
+    // Forked children share the pid in `$$` but not the real one, and
+    // leave the history file to the shell itself.
+    if shell.interactive && process::id() == shell.pid {
+        save_history(shell);
+    }
+    let _ = std::io::stdout().flush();
+    process::exit(status)
+}
 
-                    for (i, cmd) in history.iter().enumerate() {
-                        println!("{}  {}", i + 1, cmd);
-                    }
-                }
-                1 => {
-                    if let Ok(n) = args[0].parse::<usize>() {
-                        let total = history.len();
+/// Runs a trap action, leaving `$?` as it was before.
+fn run_trap(shell: &mut Shell, action: &str) {
+    let status = shell.last_status;
+    let running = std::mem::replace(&mut shell.traps.running, true);
+    for line in action.lines() {
+        run_line(shell, line);
+    }
+    shell.traps.running = running;
+    shell.last_status = status;
+}
 
-                        let start = total.saturating_sub(n);
-                        for (i, cmd) in history.iter().enumerate().skip(start) {
-                            println!("{:>4}  {}", i + 1, cmd);
-                        }
-                    } else {
-                        eprintln!("history: {}: numeric argument required", args[0]);
-                    }
-                }
-                _ => {
-                    eprintln!("history: too many arguments");
-                }
+/// Runs the actions of the trapped signals that arrived since the last
+/// check.
+fn run_pending_traps(shell: &mut Shell) {
+    for signal in trap::take_pending() {
+        if let Some(action) = shell.traps.get(Condition::Signal(signal)).map(str::to_string) {
+            run_trap(shell, &action);
+        }
+    }
+}
+
+/// Runs the `ERR`, `DEBUG` or `RETURN` trap if one is set, unless a trap
+/// action is already running.
+fn run_condition_trap(shell: &mut Shell, condition: Condition) {
+    if shell.traps.running {
+        return;
+    }
+    if let Some(action) = shell.traps.get(condition).filter(|action| !action.is_empty()).map(str::to_string) {
+        run_trap(shell, &action);
+    }
+}
+
+/// Runs one command line in the current shell context. Each pipeline
+/// records its exit status in `$?`; the line's status is the last one's.
+/// Under `set -n` a non-interactive shell only parses the line.
+fn run_line(shell: &mut Shell, line: &str) -> i32 {
+    if shell.options.verbose {
+        eprintln!("{}", line);
+    }
+    let list = match parse_list(line) {
+        Ok(list) => list,
+        Err(e) => {
+            if shell.interactive {
+                eprintln!("rustyshell: {}", e);
+            } else {
+                let file = shell.file.as_deref().unwrap_or("stdin");
+                eprintln!("{}:{}:{}: {}", file, shell.lineno, e.column, e);
             }
-            continue;
+            shell.syntax_errors += 1;
+            shell.last_status = 2;
+            return 2;
         }
+    };
+    if shell.options.noexec && !shell.interactive {
+        return shell.last_status;
+    }
 
-        if command == "type" && args.get(0).map(|s| s.as_str()) == Some("history") {
-            println!("history is a shell builtin");
-            continue;
+    let mut status = shell.last_status;
+    for and_or in &list {
+        status = if and_or.background { run_background(shell, and_or) } else { run_and_or(shell, and_or) };
+        if shell.returning.is_some() {
+            break;
         }
+    }
+    status
+}
 
-        if command == "echo" {
-            let mut cleaned_args = Vec::new();
-            let mut stdout_redirect: Option<File> = None;
-            let mut stderr_redirect: Option<File> = None;
-            let mut args = parts[1..].to_vec();
+/// Runs the pipelines of an AND-OR list, each one only if the status so far
+/// matches its `&&` or `||`. If the list fails the `ERR` trap runs and,
+/// under `set -e`, the shell exits, except when the failure came from a
+/// pipeline other than the last or from one negated with `!`.
+fn run_and_or(shell: &mut Shell, and_or: &AndOr) -> i32 {
+    let mut status = run_pipeline(shell, &and_or.first);
+    run_pending_traps(shell);
+    let mut last = &and_or.first;
+    for (connector, pipeline) in &and_or.rest {
+        if shell.returning.is_some() {
+            return status;
+        }
+        if (*connector == Connector::And) == (status == 0) {
+            status = run_pipeline(shell, pipeline);
+            run_pending_traps(shell);
+            last = pipeline;
+        }
+    }
 
-            let mut i = 0;
-            while i < args.len() {
-                if args[i] == ">" || args[i] == "1>" || args[i] == ">>" || args[i] == "1>>" {
-                    if i + 1 < args.len() {
-                        let filename = &args[i + 1];
-                        if let Some(parent) = Path::new(filename).parent() {
-                            let _ = std::fs::create_dir_all(parent);
-                        }
-                        let file = if args[i] == ">" || args[i] == "1>" {
-                            File::create(filename)
-                        } else {
-                            OpenOptions::new().append(true).create(true).open(filename)
-                        };
-                        match file {
-                            Ok(file) => {
-                                stdout_redirect = Some(file);
-                                args.drain(i..=i + 1);
-                                continue;
-                            }
-                            Err(e) => {
-                                eprintln!("{}: {}", filename, e);
-                                break;
-                            }
-                        }
-                    }
-                } else if args[i] == "2>" || args[i] == "2>>" {
-                    if i + 1 < args.len() {
-                        let filename = &args[i + 1];
-                        if let Some(parent) = Path::new(filename).parent() {
-                            let _ = std::fs::create_dir_all(parent);
-                        }
-                        let file = if args[i] == "2>" {
-                            File::create(filename)
-                        } else {
-                            OpenOptions::new().append(true).create(true).open(filename)
-                        };
-                        match file {
-                            Ok(file) => {
-                                stderr_redirect = Some(file);
-                                args.drain(i..=i + 1);
-                                continue;
-                            }
-                            Err(e) => {
-                                eprintln!("{}: {}", filename, e);
-                                break;
-                            }
-                        }
-                    }
-                }
+    let ran_last = and_or.rest.last().map_or(true, |(_, pipeline)| std::ptr::eq(last, pipeline));
+    if status != 0 && ran_last && !last.negated && shell.returning.is_none() {
+        run_condition_trap(shell, Condition::Err);
+        if shell.options.errexit {
+            exit_shell(shell, status);
+        }
+    }
+    status
+}
 
-                cleaned_args.push(args[i].clone());
-                i += 1;
+/// Runs an AND-OR list ended by `&` in a forked child and returns at once,
+/// leaving its pid in `$!` for `wait`. There is no job control, so the job
+/// reads from `/dev/null` and ignores SIGINT and SIGQUIT.
+fn run_background(shell: &mut Shell, and_or: &AndOr) -> i32 {
+    match unsafe { fork() } {
+        Ok(ForkResult::Child) => {
+            shell.traps.reset_for_subshell();
+            shell.traps.set(Condition::Signal(libc::SIGINT), "");
+            shell.traps.set(Condition::Signal(libc::SIGQUIT), "");
+            if let Ok(devnull) = File::open("/dev/null") {
+                shell.input_buffered = false;
+                unsafe { libc::dup2(devnull.as_raw_fd(), libc::STDIN_FILENO) };
             }
-
-            let output = cleaned_args.join(" ");
-            if let Some(mut file) = stdout_redirect {
-                if let Err(e) = writeln!(file, "{}", output) {
-                    if let Some(mut stderr_file) = stderr_redirect {
-                        let _ = writeln!(stderr_file, "echo: failed to write to file: {}", e);
-                    } else {
-                        eprintln!("echo: failed to write to file: {}", e);
-                    }
-                }
-            } else {
-                println!("{}", output);
+            let status = run_and_or(shell, and_or);
+            exit_shell(shell, status);
+        }
+        Ok(ForkResult::Parent { child }) => {
+            shell.last_background = Some(child.as_raw());
+            shell.jobs.push(child);
+            if shell.interactive {
+                eprintln!("[{}] {}", shell.jobs.len(), child);
             }
-            continue;
+            shell.last_status = 0;
+            0
         }
+        Err(e) => {
+            eprintln!("fork failed: {}", e);
+            1
+        }
+    }
+}
 
-        if command == "type" {
-            if let Some(arg) = args.first() {
-                if ["echo", "exit", "type", "pwd", "cd"].contains(&arg.as_str()) {
-                    println!("{} is a shell builtin", arg);
-                    continue;
-                }
-
-                let mut found = false;
-                if let Ok(path_var) = std::env::var("PATH") {
-                    found = path_var.split(':').any(|dir| {
-                        let full_path = Path::new(dir).join(arg);
-                        check_executable(&full_path, arg)
-                    });
-                }
+/// Waits for a child to finish and gives its status, using the
+/// `128 + signal` convention for one killed by a signal.
+fn wait_for(child: Pid) -> i32 {
+    match waitpid(child, None) {
+        Ok(WaitStatus::Exited(_, code)) => code,
+        Ok(WaitStatus::Signaled(_, sig, _)) => 128 + sig as i32,
+        _ => 0,
+    }
+}
 
-                if !found {
-                    let system_dirs = ["/usr/bin", "/bin", "/usr/local/bin"];
-                    found = system_dirs.iter().any(|dir| {
-                        let full_path = Path::new(dir).join(arg);
-                        check_executable(&full_path, arg)
-                    });
-                }
+fn run_pipeline(shell: &mut Shell, pipeline: &Pipeline) -> i32 {
+    run_condition_trap(shell, Condition::Debug);
+    let status = match pipeline.stages.as_slice() {
+        [stage] => execute_line(shell, stage),
+        stages => handle_pipeline(shell, stages),
+    };
+    let status = if pipeline.negated { (status == 0) as i32 } else { status };
+    shell.last_status = status;
+    status
+}
 
-                if !found {
-                    println!("{}: not found", arg);
-                }
-            } else {
-                println!("type: missing argument");
+fn execute_line(shell: &mut Shell, line: &str) -> i32 {
+    let (assignments, command_line) = split_assignments(line);
+    if command_line.is_empty() {
+        for assignment in &assignments {
+            // Traced before it is made, so `PS4=...` shows the old prefix.
+            let result = expand_assignment(shell, assignment, true).and_then(|expanded| {
+                xtrace::trace(shell, std::slice::from_ref(&expanded.traced));
+                make_assignment(shell, expanded)
+            });
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                return 1;
             }
-            continue;
         }
+        return 0;
+    }
 
-        if command == "pwd" {
-            match std::env::current_dir() {
-                Ok(path) => println!("{}", path.display()),
-                Err(err) => eprintln!("pwd: {}", err),
+    // The command's words are expanded before its prefix assignments are
+    // made, so `FOO=new echo "$FOO"` sees the old value. `[[ ... ]]`
+    // expands its operands as it evaluates them instead.
+    let parts = if is_conditional(command_line) {
+        None
+    } else {
+        match expand_command(shell, command_line) {
+            Ok(parts) => Some(parts),
+            Err(status) => return status,
+        }
+    };
+
+    // Prefix assignments (`FOO=1 cargo test`) only last for this command.
+    let saved: Vec<_> = assignments
+        .iter()
+        .map(|assignment| (assignment.name, shell.vars.lookup(assignment.name).cloned()))
+        .collect();
+    let mut traced = Vec::new();
+    let mut failed = false;
+    for assignment in &assignments {
+        match assign(shell, assignment, true) {
+            Ok(word) => traced.push(word),
+            Err(e) => {
+                eprintln!("{}", e);
+                failed = true;
+                break;
             }
-            continue;
         }
+        shell.vars.set_exported(assignment.name, true);
+    }
+    let status = match parts {
+        _ if failed => 1,
+        Some(parts) => execute_command(shell, parts, traced),
+        None => run_conditional(shell, command_line, &traced),
+    };
+    for (name, previous) in saved.into_iter().rev() {
+        shell.vars.restore(name, previous);
+    }
+    status
+}
 
-        if command == "cd" {
-            if let Some(path) = args.first() {
-                let target_dir = if *path == "~" {
-                    match std::env::var("HOME") {
-                        Ok(home) => home,
-                        Err(_) => {
-                            eprintln!("cd: HOME not set");
-                            continue;
-                        }
-                    }
-                } else {
-                    path.to_string()
-                };
+/// Expands the words of a simple command, or gives the status to fail with.
+fn expand_command(shell: &mut Shell, trimmed: &str) -> Result<Vec<String>, i32> {
+    tokenize(trimmed, shell).map_err(|e| {
+        eprintln!("{}", e);
+        // `set -u`: a script stops at the first unset variable.
+        if shell.options.nounset && !shell.interactive && e.ends_with(UNBOUND) {
+            exit_shell(shell, 1);
+        }
+        1
+    })
+}
 
-                if let Err(_) = std::env::set_current_dir(&target_dir) {
-                    eprintln!("cd: {}: No such file or directory", target_dir);
-                }
+/// Runs one simple command from its expanded words. `traced` holds its
+/// prefix assignments, already made, for the `set -x` trace.
+fn execute_command(shell: &mut Shell, parts: Vec<String>, mut traced: Vec<String>) -> i32 {
+    traced.extend(traced_words(&parts));
+    xtrace::trace(shell, &traced);
+    if parts.is_empty() {
+        return 0;
+    }
+    // Already expanded, so the command's own `$_` saw the previous value.
+    shell.last_argument = parts[parts.len() - 1].clone();
+    run_words(shell, parts)
+}
+
+/// Runs a command from its expanded words, after dropping the `command` and
+/// `builtin` words in front of it.
+fn run_words(shell: &mut Shell, parts: Vec<String>) -> i32 {
+    let (start, default_path) = match lookup_prefix(&parts) {
+        Ok(prefix) => prefix,
+        Err(status) => return status,
+    };
+    let parts = parts[start..].to_vec();
+    if default_path {
+        let saved = shell.vars.lookup("PATH").cloned();
+        let _ = shell.vars.set("PATH", DEFAULT_PATH);
+        let status = run_words(shell, parts);
+        shell.vars.restore("PATH", saved);
+        return status;
+    }
+
+    let command = &parts[0];
+
+    if is_builtin(command) {
+        let mut args_vec = parts.clone();
+        let (mut stdout_redirect, mut stderr_redirect) = match open_redirections(&mut args_vec) {
+            Ok(files) => files,
+            Err(e) => {
+                eprintln!("{}", e);
+                return 1;
+            }
+        };
+        let args: Vec<&str> = args_vec.iter().skip(1).map(|s| s.as_str()).collect();
+        return run_builtin(shell, command, &args, stdout_redirect.as_mut(), stderr_redirect.as_mut());
+    }
+
+    // External command execution
+    let path_var = shell.vars.get("PATH").map(str::to_string);
+    let mut args_vec = parts.clone();
+    let (stderr_path_opt, stderr_append) = parse_stderr_redirection(&mut args_vec);
+    let mut stderr_file: Option<File> = None;
+
+    if let Some(ref stderr_path) = stderr_path_opt {
+        if let Some(parent) = Path::new(stderr_path).parent() {
+            let _ = std::fs::create_dir_all(parent);
+        }
+        let result = if stderr_append {
+            OpenOptions::new().append(true).create(true).open(stderr_path)
+        } else {
+            File::create(stderr_path)
+        };
+        match result {
+            Ok(file) => stderr_file = Some(file),
+            Err(e) => {
+                eprintln!("{}: {}", stderr_path, e);
             }
-            continue;
         }
+    }
 
-        // External command execution
-        if let Ok(path_var) = std::env::var("PATH") {
-            let mut args_vec = parts.clone();
-            let (stderr_path_opt, stderr_append) = parse_stderr_redirection(&mut args_vec);
-            let mut stderr_file: Option<File> = None;
+    let mut stdout_redirect: Option<File> = None;
+
+    let command = if !args_vec.is_empty() {
+        args_vec[0].clone()
+    } else {
+        return 0;
+    };
 
-            if let Some(ref stderr_path) = stderr_path_opt {
-                if let Some(parent) = Path::new(stderr_path).parent() {
-                    let _ = std::fs::create_dir_all(parent);
+    let mut i = 0;
+    while i < args_vec.len() {
+        if (args_vec[i] == ">" || args_vec[i] == "1>" || args_vec[i] == ">>" || args_vec[i] == "1>>")
+            && i + 1 < args_vec.len()
+        {
+            let filename = args_vec[i + 1].clone();
+            let result = if args_vec[i].ends_with(">>") {
+                OpenOptions::new().append(true).create(true).open(&filename)
+            } else {
+                File::create(&filename)
+            };
+            match result {
+                Ok(file) => {
+                    stdout_redirect = Some(file);
+                    args_vec.drain(i..=i + 1);
+                    continue;
                 }
-                let result = if stderr_append {
-                    OpenOptions::new().append(true).create(true).open(stderr_path)
-                } else {
-                    File::create(stderr_path)
-                };
-                match result {
-                    Ok(file) => stderr_file = Some(file),
-                    Err(e) => {
-                        eprintln!("{}: {}", stderr_path, e);
-                    }
+                Err(e) => {
+                    eprintln!("{}: {}", filename, e);
+                    break;
                 }
             }
+        }
+        i += 1;
+    }
 
-            let mut stdout_redirect: Option<File> = None;
+    if let Some(full_path) = shell.hash.find(&command, path_var.as_deref()) {
+        if let Err(status) = check_runnable(&command, &full_path) {
+            return status;
+        }
+        let configure = |cmd: &mut Command| {
+            cmd.args(&args_vec[1..]);
+            cmd.env_clear();
+            cmd.envs(shell.vars.exported());
+            // The standard library resets SIGPIPE in the child, so
+            // signals ignored with `trap '' SIG` are ignored again here.
+            let ignored = shell.traps.ignored();
+            unsafe {
+                cmd.pre_exec(move || {
+                    for &signal in &ignored {
+                        libc::signal(signal, libc::SIG_IGN);
+                    }
+                    Ok(())
+                });
+            }
 
-            let command = if !args_vec.is_empty() {
-                args_vec[0].clone()
+            if let Some(ref file) = stdout_redirect {
+                cmd.stdout(Stdio::from(file.try_clone().unwrap()));
             } else {
-                continue;
-            };
+                cmd.stdout(Stdio::piped());
+            }
 
-            let mut i = 0;
-            while i < args_vec.len() {
-                if args_vec[i] == ">" || args_vec[i] == "1>" || args_vec[i] == ">>" || args_vec[i] == "1>>" {
-                    if i + 1 < args_vec.len() {
-                        let filename = args_vec[i + 1].clone();
-                        let result = if args_vec[i].ends_with(">>") {
-                            OpenOptions::new().append(true).create(true).open(&filename)
-                        } else {
-                            File::create(&filename)
-                        };
-                        match result {
-                            Ok(file) => {
-                                stdout_redirect = Some(file);
-                                args_vec.drain(i..=i + 1);
-                                continue;
-                            }
-                            Err(e) => {
-                                eprintln!("{}: {}", filename, e);
-                                break;
-                            }
-                        }
-                    }
+            if let Some(file) = &stderr_file {
+                cmd.stderr(Stdio::from(file.try_clone().unwrap()));
+            } else {
+                cmd.stderr(Stdio::piped());
+            }
+        };
+        // A text file the kernel can't execute, failing with ENOEXEC, is
+        // run as a script.
+        let mut cmd = Command::new(&full_path);
+        configure(&mut cmd);
+        exec_directly(&mut cmd, &full_path, &args_vec, shell);
+        let child = match cmd.spawn() {
+            Err(e) if e.raw_os_error() == Some(libc::ENOEXEC) => {
+                if binary_file(&full_path) {
+                    eprintln!("{}: cannot execute binary file: Exec format error", command);
+                    return 126;
                 }
-                i += 1;
+                script_command(&full_path).and_then(|mut cmd| {
+                    configure(&mut cmd);
+                    cmd.spawn()
+                })
             }
+            child => child,
+        };
 
-            let mut found = false;
-            for dir in path_var.split(':') {
-                let full_path = Path::new(dir).join(&command);
-                if full_path.exists() && full_path.is_file()
-                    && full_path.metadata().map(|m| m.permissions().mode() & 0o111 != 0).unwrap_or(false) {
-                    found = true;
+        return match child.and_then(|child| child.wait_with_output()) {
+            Ok(output) => {
+                if stdout_redirect.is_none() {
+                    print!("{}", String::from_utf8_lossy(&output.stdout));
+                }
+                if stderr_file.is_none() {
+                    eprint!("{}", String::from_utf8_lossy(&output.stderr));
+                }
+                exit_code(output.status)
+            }
+            Err(e) => exec_failure(&command, &e),
+        };
+    }
 
-                    let mut cmd = Command::new(full_path);
-                    cmd.arg0(&command);
-                    cmd.args(&args_vec[1..]);
+    println!("{}: command not found", command);
+    127
+}
 
-                    if let Some(ref file) = stdout_redirect {
-                        cmd.stdout(Stdio::from(file.try_clone().unwrap()));
-                    } else {
-                        cmd.stdout(Stdio::piped());
-                    }
+/// Checks that `program`, the file the command `name` resolved to, can be
+/// run, printing why not and returning the exit status otherwise: 127 if it
+/// doesn't exist, 126 if it is a directory or not executable.
+fn check_runnable(name: &str, program: &Path) -> Result<(), i32> {
+    match fs::metadata(program) {
+        Err(e) => Err(exec_failure(name, &e)),
+        Ok(metadata) if metadata.is_dir() => {
+            eprintln!("{}: Is a directory", name);
+            Err(126)
+        }
+        Ok(_) if nix::unistd::access(program, nix::unistd::AccessFlags::X_OK).is_err() => {
+            eprintln!("{}: Permission denied", name);
+            Err(126)
+        }
+        Ok(_) => Ok(()),
+    }
+}
 
-                    if let Some(file) = &stderr_file {
-                        cmd.stderr(Stdio::from(file.try_clone().unwrap()));
-                    } else {
-                        cmd.stderr(Stdio::piped());
-                    }
+/// Makes `cmd` exec `program` with `execve`, passing `argv` and the
+/// exported variables, so the kernel alone decides whether the file can
+/// run. The standard library execs through `execvp`, which hands a file the
+/// kernel rejects with ENOEXEC to `/bin/sh` instead of failing; here that
+/// error comes back from `spawn`.
+fn exec_directly(cmd: &mut Command, program: &Path, argv: &[String], shell: &Shell) {
+    let to_cstring = |text: &str| CString::new(text).unwrap_or_default();
+    let program = CString::new(program.as_os_str().as_bytes()).unwrap_or_default();
+    let argv: Vec<CString> = argv.iter().map(|arg| to_cstring(arg)).collect();
+    let env: Vec<CString> =
+        shell.vars.exported().map(|(name, value)| to_cstring(&format!("{}={}", name, value))).collect();
+    let args = ExecArgs::new(program, argv, env);
+    unsafe {
+        cmd.pre_exec(move || Err(args.exec()));
+    }
+}
 
-                    match cmd.spawn().and_then(|child| child.wait_with_output()) {
-                        Ok(output) => {
-                            if stdout_redirect.is_none() {
-                                print!("{}", String::from_utf8_lossy(&output.stdout));
-                            }
-                            if stderr_file.is_none() {
-                                eprint!("{}", String::from_utf8_lossy(&output.stderr));
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Failed to execute {}: {}", command, e);
-                        }
+/// The arguments of an `execve` made between `fork` and exec, where the
+/// child of a multithreaded process must not allocate: the strings and the
+/// null-terminated pointer arrays into them are all built beforehand.
+struct ExecArgs {
+    program: CString,
+    argv: Vec<*const libc::c_char>,
+    env: Vec<*const libc::c_char>,
+    _strings: (Vec<CString>, Vec<CString>),
+}
+
+// The pointers only refer to the strings the value owns, which it never
+// changes.
+unsafe impl Send for ExecArgs {}
+unsafe impl Sync for ExecArgs {}
+
+impl ExecArgs {
+    fn new(program: CString, argv: Vec<CString>, env: Vec<CString>) -> Self {
+        let pointers = |strings: &[CString]| {
+            strings.iter().map(|text| text.as_ptr()).chain(std::iter::once(std::ptr::null())).collect()
+        };
+        ExecArgs { program, argv: pointers(&argv), env: pointers(&env), _strings: (argv, env) }
+    }
+
+    /// Replaces the process, returning only the error if that fails.
+    fn exec(&self) -> std::io::Error {
+        unsafe {
+            libc::execve(self.program.as_ptr(), self.argv.as_ptr(), self.env.as_ptr());
+        }
+        std::io::Error::last_os_error()
+    }
+}
+
+/// Whether `program`, which the kernel can't execute, is a binary rather
+/// than text that can be run as a script: a NUL byte in its first line,
+/// within the first 80 bytes, marks one.
+fn binary_file(program: &Path) -> bool {
+    let mut head = [0u8; 80];
+    let len = File::open(program).and_then(|mut file| file.read(&mut head)).unwrap_or(0);
+    let first_line = head[..len].split(|&byte| byte == b'\n').next().unwrap_or_default();
+    first_line.contains(&0)
+}
+
+/// The command that runs `script`, a text file without a `#!` line, in a
+/// fresh shell, which takes the arguments added after it as `$1`, `$2`, ….
+fn script_command(script: &Path) -> std::io::Result<Command> {
+    let mut cmd = Command::new(env::current_exe()?);
+    cmd.arg(script);
+    Ok(cmd)
+}
+
+/// Reports that the command `name` could not be run, returning 127 if its
+/// file doesn't exist and 126 for any other failure.
+fn exec_failure(name: &str, err: &std::io::Error) -> i32 {
+    eprintln!("{}: {}", name, os_error(err));
+    if err.kind() == std::io::ErrorKind::NotFound {
+        127
+    } else {
+        126
+    }
+}
+
+/// The `PATH` that `command -p` searches, where the standard utilities are.
+const DEFAULT_PATH: &str = "/usr/bin:/bin";
+
+/// How many leading words of `parts` are `command` and `builtin` prefixes,
+/// which only say how the name after them is found, and whether `command -p`
+/// asked for the default `PATH`. `command -v`, `command -V`, an invalid
+/// option or a prefix with no name after it are left to run as builtins.
+/// `builtin` followed by something that isn't one is an error.
+fn lookup_prefix(parts: &[String]) -> Result<(usize, bool), i32> {
+    let mut start = 0;
+    let mut default_path = false;
+    loop {
+        match parts.get(start).map(String::as_str) {
+            Some("command") => {
+                let mut next = start + 1;
+                let mut flags = String::new();
+                while let Some(arg) = parts.get(next).filter(|arg| arg.starts_with('-') && arg.len() > 1) {
+                    next += 1;
+                    if arg == "--" {
+                        break;
                     }
-                    break;
+                    flags.push_str(&arg[1..]);
+                }
+                if next == parts.len() || flags.contains(['v', 'V']) || flags.chars().any(|flag| flag != 'p') {
+                    return Ok((start, default_path));
                 }
+                default_path |= flags.contains('p');
+                start = next;
             }
-
-            if !found {
-                println!("{}: command not found", command);
+            Some("builtin") if start + 1 < parts.len() => {
+                let name = &parts[start + 1];
+                if !is_builtin(name) {
+                    eprintln!("builtin: {}: not a shell builtin", name);
+                    return Err(1);
+                }
+                start += 1;
             }
+            _ => return Ok((start, default_path)),
+        }
+    }
+}
+
+/// Runs `command` if it is a `[[ ... ]]` conditional, returning its status:
+/// 0 if the expression is true, 1 if false and 2 on an error. Returns
+/// `None` for any other command.
+fn is_conditional(command: &str) -> bool {
+    command.strip_prefix("[[").is_some_and(|rest| rest.starts_with(char::is_whitespace))
+}
+
+fn run_conditional(shell: &mut Shell, command: &str, traced: &[String]) -> i32 {
+    let rest = &command["[[".len()..];
+    let Some(expression) = rest.trim_end().strip_suffix("]]").filter(|rest| rest.ends_with(char::is_whitespace)) else {
+        eprintln!("rustyshell: syntax error in conditional expression: expected `]]' at the end");
+        return 2;
+    };
+
+    let mut traced = traced.to_vec();
+    traced.extend(["[[".to_string(), expression.trim().to_string(), "]]".to_string()]);
+    xtrace::trace(shell, &traced);
+    match cond::double_bracket(shell, expression) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            eprintln!("rustyshell: {}", e);
+            2
         }
     }
 }
 
-fn tokenize(input: &str) -> Vec<String> {
-    let mut tokens = Vec::new();
-    let mut current = String::new();
+fn tokenize(input: &str, shell: &mut Shell) -> Result<Vec<String>, String> {
+    expand_words(input, shell, true)
+}
+
+/// Expands `input` as a single word, keeping blanks: used for assignment
+/// values and array subscripts, which are never split into words.
+fn expand_string(input: &str, shell: &mut Shell) -> Result<String, String> {
+    Ok(expand_words(input, shell, false)?.concat())
+}
+
+/// Expands parameters and removes quotes. With `split`, blanks separate
+/// words and unquoted expansion results are split further at `$IFS`
+/// characters; without it the input is a single word and only quote removal
+/// and expansion happen.
+fn expand_words(input: &str, shell: &mut Shell, split: bool) -> Result<Vec<String>, String> {
+    let mut words = Words::new(split && !shell.options.noglob);
     let mut chars = input.chars().peekable();
     let mut in_single_quotes = false;
     let mut in_double_quotes = false;
+    // Set while the current double quotes hold nothing but a `"$@"` with no
+    // elements, which expands to no word at all rather than an empty one.
+    let mut only_empty_list = false;
 
     while let Some(ch) = chars.next() {
         match ch {
-            '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
-            '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+            '\'' if !in_double_quotes => {
+                in_single_quotes = !in_single_quotes;
+                words.mark();
+            }
+            '"' if !in_single_quotes => {
+                if in_double_quotes && !only_empty_list {
+                    words.mark();
+                }
+                in_double_quotes = !in_double_quotes;
+                only_empty_list = false;
+            }
             '\\' => {
                 if in_single_quotes {
-                    current.push('\\');
+                    words.push('\\');
                 } else if let Some(next_ch) = chars.next() {
                     if in_double_quotes && ['\\', '"', '$', '\n'].contains(&next_ch) {
                         if next_ch != '\n' {
-                            current.push(next_ch);
+                            words.push(next_ch);
                         }
                     } else if !in_double_quotes && !in_single_quotes {
-                        current.push(next_ch);
+                        words.push(next_ch);
                     } else {
-                        current.push('\\');
-                        current.push(next_ch);
+                        words.push('\\');
+                        words.push(next_ch);
                     }
                 } else {
-                    current.push('\\');
+                    words.push('\\');
                 }
             }
-            ' ' | '\t' if !in_single_quotes && !in_double_quotes => {
-                if !current.is_empty() {
-                    tokens.push(current.clone());
-                    current.clear();
+            '$' if !in_single_quotes => match expand_parameter(&mut chars, shell, in_double_quotes)? {
+                Some(Expansion::Text(value)) if in_double_quotes || !split => words.push_str(&value),
+                Some(Expansion::Text(value)) => words.push_split(&value, ifs(shell)),
+                Some(Expansion::Fields(fields)) if in_double_quotes => {
+                    only_empty_list = fields.is_empty();
+                    let mut fields = fields.into_iter();
+                    if let Some(first) = fields.next() {
+                        words.push_str(&first);
+                    }
+                    for field in fields {
+                        if split {
+                            words.finish();
+                        } else {
+                            words.push(' ');
+                        }
+                        words.push_str(&field);
+                    }
+                }
+                Some(Expansion::Fields(fields)) if !split => words.push_str(&fields.join(" ")),
+                Some(Expansion::Fields(fields)) => {
+                    let fields_len = fields.len();
+                    for (i, field) in fields.iter().enumerate() {
+                        words.push_split(field, ifs(shell));
+                        if i + 1 < fields_len {
+                            words.finish();
+                        }
+                    }
                 }
+                None => words.push('$'),
+            },
+            // `NAME=(...)`: keep a compound array assignment together as one
+            // word, unexpanded, for the assignment code to pick apart.
+            '(' if !in_single_quotes && !in_double_quotes && is_assignment_prefix(words.current()) => {
+                words.push('(');
+                words.push_str(&read_parenthesized(&mut chars));
+            }
+            ' ' | '\t' if split && !in_single_quotes && !in_double_quotes => {
+                words.finish();
                 while let Some(&next) = chars.peek() {
                     if next == ' ' || next == '\t' {
                         chars.next();
@@ -461,146 +1047,1975 @@ fn tokenize(input: &str) -> Vec<String> {
                     }
                 }
             }
-            _ => current.push(ch),
+            _ if in_single_quotes || in_double_quotes => words.push(ch),
+            _ => words.push_unquoted(ch),
         }
     }
 
-    if !current.is_empty() {
-        tokens.push(current);
-    }
-
-    tokens
+    Ok(words.into_words())
 }
 
-fn parse_stderr_redirection(args: &mut Vec<String>) -> (Option<String>, bool) {
-    let mut stderr_file = None;
-    let mut append = false;
-    let mut i = 0;
+/// Whether `word` is `NAME=`, `NAME+=` or `NAME[subscript]=` with nothing
+/// after the `=` yet, i.e. a `(` here starts a compound assignment.
+fn is_assignment_prefix(word: &str) -> bool {
+    word.strip_suffix('=')
+        .is_some_and(|target| parse_assignment(&format!("{}=", target)).is_some())
+}
 
+/// Reads raw text up to and including the `)` that closes an already
+/// consumed `(`, honouring quotes and nested parentheses.
+fn read_parenthesized(chars: &mut Peekable<Chars>) -> String {
+    let mut text = String::new();
+    let mut depth = 0;
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+
+    while let Some(ch) = chars.next() {
+        text.push(ch);
+        match ch {
+            '\\' if !in_single_quotes => {
+                if let Some(next) = chars.next() {
+                    text.push(next);
+                }
+            }
+            '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+            '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+            '(' if !in_single_quotes && !in_double_quotes => depth += 1,
+            ')' if !in_single_quotes && !in_double_quotes => {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+            }
+            _ => {}
+        }
+    }
+    text
+}
+
+/// A `NAME=value`, `NAME+=value`, `NAME[subscript]=value` or
+/// `NAME=(...)` word, split into its parts but not yet expanded.
+struct Assignment<'a> {
+    name: &'a str,
+    subscript: Option<&'a str>,
+    append: bool,
+    value: &'a str,
+}
+
+fn parse_assignment(word: &str) -> Option<Assignment<'_>> {
+    let (target, value) = word.split_once('=')?;
+    let (target, append) = match target.strip_suffix('+') {
+        Some(target) => (target, true),
+        None => (target, false),
+    };
+    let (name, subscript) = match target.split_once('[') {
+        Some((name, subscript)) => (name, Some(subscript.strip_suffix(']')?)),
+        None => (target, None),
+    };
+    is_valid_name(name).then_some(Assignment { name, subscript, append, value })
+}
+
+/// Performs an assignment. Words taken straight from the command line are
+/// still raw and have their value and subscript expanded here (`expand`);
+/// arguments to `declare` and friends were expanded with the rest of the
+/// command, apart from the body of a compound `(...)` value. Returns the
+/// assignment as `set -x` shows it, with the values expanded.
+fn assign(shell: &mut Shell, assignment: &Assignment, expand: bool) -> Result<String, String> {
+    let expanded = expand_assignment(shell, assignment, expand)?;
+    let traced = expanded.traced.clone();
+    make_assignment(shell, expanded)?;
+    Ok(traced)
+}
+
+/// An assignment with its subscript and value expanded, not yet made.
+struct ExpandedAssignment<'a> {
+    name: &'a str,
+    subscript: Option<String>,
+    append: bool,
+    value: AssignedValue,
+    /// The assignment as `set -x` shows it.
+    traced: String,
+}
+
+enum AssignedValue {
+    Scalar(String),
+    /// The elements of a compound `(...)` value, with any explicit keys.
+    Compound(Vec<(Option<String>, String)>),
+}
+
+/// Expands the parts of `assignment` as `assign` describes.
+fn expand_assignment<'a>(
+    shell: &mut Shell,
+    assignment: &Assignment<'a>,
+    expand: bool,
+) -> Result<ExpandedAssignment<'a>, String> {
+    let expand_text = |shell: &mut Shell, text: &str| {
+        if expand {
+            expand_string(text, shell)
+        } else {
+            Ok(text.to_string())
+        }
+    };
+    let subscript = assignment.subscript.map(|text| expand_text(shell, text)).transpose()?;
+    let name = assignment.name;
+
+    let compound = assignment
+        .value
+        .strip_prefix('(')
+        .and_then(|value| value.strip_suffix(')'))
+        .filter(|_| subscript.is_none());
+    let mut traced = match &subscript {
+        Some(subscript) => format!("{}[{}]", name, subscript),
+        None => name.to_string(),
+    };
+    traced.push_str(if assignment.append { "+=" } else { "=" });
+    let value = if let Some(body) = compound {
+        let elements = parse_compound(body, shell)?;
+        let words: Vec<String> = elements
+            .iter()
+            .map(|(key, value)| match key {
+                Some(key) => format!("[{}]={}", key, xtrace::quote(value)),
+                None => xtrace::quote(value),
+            })
+            .collect();
+        traced.push_str(&format!("({})", words.join(" ")));
+        AssignedValue::Compound(elements)
+    } else {
+        let value = expand_text(shell, assignment.value)?;
+        traced.push_str(&xtrace::quote(&value));
+        AssignedValue::Scalar(value)
+    };
+    Ok(ExpandedAssignment { name, subscript, append: assignment.append, value, traced })
+}
+
+fn make_assignment(shell: &mut Shell, expanded: ExpandedAssignment) -> Result<(), String> {
+    let name = expanded.name;
+    let result = match (expanded.value, expanded.subscript, expanded.append) {
+        (AssignedValue::Compound(elements), _, append) => shell.vars.set_array(name, elements, append),
+        (AssignedValue::Scalar(value), Some(subscript), false) => shell.vars.set_element(name, &subscript, value),
+        (AssignedValue::Scalar(value), Some(subscript), true) => shell.vars.append_element(name, &subscript, &value),
+        (AssignedValue::Scalar(value), None, false) => shell.vars.set(name, value),
+        (AssignedValue::Scalar(value), None, true) => shell.vars.append(name, &value),
+    };
+    result.map_err(|e| e.to_string())?;
+    after_assignment(shell, name);
+    Ok(())
+}
+
+/// Follows up any assignment to `name`: `set -a` exports it, and a dynamic
+/// variable such as `RANDOM` reacts to its new value.
+fn after_assignment(shell: &mut Shell, name: &str) {
+    if shell.options.allexport {
+        shell.vars.set_exported(name, true);
+    }
+    if let Some(value) = shell.vars.get(name).map(str::to_string) {
+        shell.dynamic.assigned(name, &value);
+    }
+}
+
+/// Expands the body of `NAME=(...)` into its elements. `[key]=value` words
+/// give an explicit subscript; any other word is expanded like a command
+/// argument, so `"${other[@]}"` contributes one element per field.
+fn parse_compound(body: &str, shell: &mut Shell) -> Result<Vec<(Option<String>, String)>, String> {
+    let mut elements = Vec::new();
+    let mut rest = body;
+    while let Some((word, remainder)) = next_raw_word(rest) {
+        rest = remainder;
+        if let Some((key, value)) = word.strip_prefix('[').and_then(|word| word.split_once("]=")) {
+            elements.push((Some(expand_string(key, shell)?), expand_string(value, shell)?));
+            continue;
+        }
+        for field in tokenize(word, shell)? {
+            elements.push((None, field));
+        }
+    }
+    Ok(elements)
+}
+
+/// Splits leading assignment words off a command line, returning them and
+/// the rest of the line. Assignment words are recognised on the raw text, so
+/// a quoted `"A=b"` is an ordinary argument.
+fn split_assignments(line: &str) -> (Vec<Assignment<'_>>, &str) {
+    let mut assignments = Vec::new();
+    let mut rest = line.trim_start();
+
+    while let Some((word, remainder)) = next_raw_word(rest) {
+        let Some(assignment) = parse_assignment(word) else {
+            break;
+        };
+        assignments.push(assignment);
+        rest = remainder.trim_start();
+    }
+
+    (assignments, rest)
+}
+
+/// Returns the first unexpanded word of `input` and the text after it, using
+/// the same quoting rules as `tokenize`. The parenthesised body of a
+/// `NAME=(...)` stays part of the word, as do blanks in the subscript of
+/// `NAME[key]=value` or of a `[key]=value` word in such a body.
+fn next_raw_word(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim_start_matches([' ', '\t', '\n']);
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+    let mut escaped = false;
+    let mut depth = 0;
+    let mut in_subscript = false;
+    let mut previous = None;
+
+    for (i, ch) in input.char_indices() {
+        if escaped {
+            escaped = false;
+            previous = Some(ch);
+            continue;
+        }
+        let unquoted = !in_single_quotes && !in_double_quotes;
+        match ch {
+            '\\' if !in_single_quotes => escaped = true,
+            '\'' if !in_double_quotes => in_single_quotes = !in_single_quotes,
+            '"' if !in_single_quotes => in_double_quotes = !in_double_quotes,
+            '(' if unquoted && (depth > 0 || previous == Some('=')) => depth += 1,
+            ')' if unquoted && depth > 0 => depth -= 1,
+            '[' if unquoted && depth == 0 && (i == 0 || is_valid_name(&input[..i])) => in_subscript = true,
+            ']' if unquoted && in_subscript => in_subscript = false,
+            ' ' | '\t' | '\n' if unquoted && depth == 0 && !in_subscript => {
+                return Some((&input[..i], &input[i..]));
+            }
+            _ => {}
+        }
+        previous = Some(ch);
+    }
+
+    if input.is_empty() {
+        None
+    } else {
+        Some((input, ""))
+    }
+}
+
+fn parse_stderr_redirection(args: &mut Vec<String>) -> (Option<String>, bool) {
+    let mut stderr_file = None;
+    let mut append = false;
+    let mut i = 0;
+
+    while i < args.len() {
+        if (args[i] == "2>" || args[i] == "2>>") && i + 1 < args.len() {
+            stderr_file = Some(args[i + 1].clone());
+            append = args[i] == "2>>";
+            args.drain(i..=i + 1);
+            continue;
+        }
+        i += 1;
+    }
+
+    (stderr_file, append)
+}
+
+/// Strips `>`, `>>`, `2>` and `2>>` redirections (and their targets) out of
+/// `args`, returning the opened stdout and stderr files.
+fn open_redirections(args: &mut Vec<String>) -> Result<(Option<File>, Option<File>), String> {
+    let mut stdout_redirect = None;
+    let mut stderr_redirect = None;
+    let mut i = 0;
+
+    while i < args.len() {
+        let op = args[i].as_str();
+        if is_redirection(op) && i + 1 < args.len() {
+            let filename = &args[i + 1];
+            if let Some(parent) = Path::new(filename).parent() {
+                let _ = std::fs::create_dir_all(parent);
+            }
+            let file = if op.ends_with(">>") {
+                OpenOptions::new().append(true).create(true).open(filename)
+            } else {
+                File::create(filename)
+            }
+            .map_err(|e| format!("{}: {}", filename, e))?;
+            if op.starts_with('2') {
+                stderr_redirect = Some(file);
+            } else {
+                stdout_redirect = Some(file);
+            }
+            args.drain(i..=i + 1);
+            continue;
+        }
+        i += 1;
+    }
+
+    Ok((stdout_redirect, stderr_redirect))
+}
+
+fn is_redirection(word: &str) -> bool {
+    matches!(word, ">" | "1>" | ">>" | "1>>" | "2>" | "2>>")
+}
+
+/// The words of a command quoted for `set -x`, which leaves out its
+/// redirections and their targets.
+fn traced_words(parts: &[String]) -> Vec<String> {
+    let mut traced = Vec::new();
+    let mut words = parts.iter().peekable();
+    while let Some(word) = words.next() {
+        if is_redirection(word) && words.peek().is_some() {
+            words.next();
+            continue;
+        }
+        traced.push(xtrace::quote(word));
+    }
+    traced
+}
+
+/// Maps a child's exit status to the value reported in `$?`, using the
+/// `128 + signal` convention for children killed by a signal.
+fn exit_code(status: ExitStatus) -> i32 {
+    status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0))
+}
+
+/// Looks up the file named by `source`/`.`. Names without a slash are searched
+/// for in `PATH` first and then in the current directory.
+fn find_source_file(shell: &Shell, name: &str) -> Option<PathBuf> {
+    if !name.contains('/') {
+        if let Some(path_var) = shell.vars.get("PATH") {
+            for dir in path_var.split(':') {
+                let candidate = Path::new(dir).join(name);
+                if candidate.is_file() {
+                    return Some(candidate);
+                }
+            }
+        }
+    }
+
+    let candidate = Path::new(name);
+    if candidate.is_file() {
+        Some(candidate.to_path_buf())
+    } else {
+        None
+    }
+}
+
+/// Runs the commands in `path` in the current execution context. Positional
+/// parameters are replaced by `args` for the duration of the file when any are
+/// given, and a `return` in the file stops it early.
+fn source_file(shell: &mut Shell, path: &Path, args: &[&str]) -> i32 {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            eprintln!("source: {}: {}", path.display(), e);
+            return 1;
+        }
+    };
+
+    let saved_positional = if args.is_empty() {
+        None
+    } else {
+        Some(std::mem::replace(
+            &mut shell.positional,
+            args.iter().map(|s| s.to_string()).collect(),
+        ))
+    };
+
+    shell.source_depth += 1;
+    let saved_lineno = shell.lineno;
+    let saved_file = shell.file.replace(path.display().to_string());
+    let mut status = 0;
+    for (i, line) in contents.lines().enumerate() {
+        // Leading blanks are kept so syntax errors give the right column.
+        let line = line.trim_end();
+        if line.trim_start().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        shell.lineno = i + 1;
+        status = run_line(shell, line);
+        if let Some(code) = shell.returning.take() {
+            status = code;
+            break;
+        }
+    }
+    // Only `source`, not the script itself, triggers the RETURN trap.
+    if shell.nesting() > 0 {
+        run_condition_trap(shell, Condition::Return);
+    }
+    shell.source_depth -= 1;
+    shell.lineno = saved_lineno;
+    shell.file = saved_file;
+
+    if let Some(positional) = saved_positional {
+        shell.positional = positional;
+    }
+    status
+}
+
+/// Points the process's stdout or stderr (`fd`) at `file` while `f` runs,
+/// so output from commands nested inside a builtin (such as `source`) is
+/// redirected too.
+fn with_fd_redirected<T>(fd: libc::c_int, file: Option<&mut File>, f: impl FnOnce() -> T) -> T {
+    let Some(file) = file else {
+        return f();
+    };
+
+    let _ = std::io::stdout().flush();
+    let saved = unsafe { libc::dup(fd) };
+    unsafe { libc::dup2(file.as_raw_fd(), fd) };
+    let result = f();
+    let _ = std::io::stdout().flush();
+    if saved >= 0 {
+        unsafe {
+            libc::dup2(saved, fd);
+            libc::close(saved);
+        }
+    }
+    result
+}
+
+fn run_builtin(
+    shell: &mut Shell,
+    cmd: &str,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    match cmd {
+        "echo" => builtin_echo(shell, args, stdout_redirect),
+        "pwd" => builtin_pwd(shell, args, stdout_redirect, stderr_redirect),
+        "cd" => builtin_cd(shell, args, stdout_redirect, stderr_redirect),
+        "exit" => {
+            let status = match args.first() {
+                Some(arg) => match arg.parse::<i32>() {
+                    Ok(n) => n & 0xff,
+                    Err(_) => {
+                        let _ = writeln!(error_stream(stderr_redirect), "exit: {}: numeric argument required", arg);
+                        2
+                    }
+                },
+                None => shell.last_status,
+            };
+            exit_shell(shell, status);
+        }
+        "type" => builtin_type(shell, args, stdout_redirect, stderr_redirect),
+        "command" => builtin_command(shell, args, stdout_redirect, stderr_redirect),
+        "hash" => builtin_hash(shell, args, stdout_redirect, stderr_redirect),
+        "history" => builtin_history(shell, args, stdout_redirect, stderr_redirect),
+        "wait" => builtin_wait(shell, args, stderr_redirect),
+        "builtin" => match args.split_first() {
+            Some((name, rest)) if is_builtin(name) => run_builtin(shell, name, rest, stdout_redirect, stderr_redirect),
+            Some((name, _)) => {
+                let _ = writeln!(error_stream(stderr_redirect), "builtin: {}: not a shell builtin", name);
+                1
+            }
+            None => 0,
+        },
+        "source" | "." => {
+            let Some(name) = args.first() else {
+                let _ = writeln!(error_stream(stderr_redirect), "{}: filename argument required", cmd);
+                return 2;
+            };
+            let Some(path) = find_source_file(shell, name) else {
+                let _ = writeln!(error_stream(stderr_redirect), "{}: {}: file not found", cmd, name);
+                return 1;
+            };
+            // The redirections apply to the whole file, including the
+            // error for one that can't be read.
+            with_fd_redirected(libc::STDERR_FILENO, stderr_redirect, || {
+                with_fd_redirected(libc::STDOUT_FILENO, stdout_redirect, || source_file(shell, &path, &args[1..]))
+            })
+        }
+        "return" => {
+            let status = match args.first() {
+                Some(arg) => match arg.parse::<i32>() {
+                    Ok(n) => n & 0xff,
+                    Err(_) => {
+                        eprintln!("return: {}: numeric argument required", arg);
+                        2
+                    }
+                },
+                None => shell.last_status,
+            };
+            if shell.nesting() == 0 {
+                eprintln!("return: can only `return' from a function or sourced script");
+                return 1;
+            }
+            shell.returning = Some(status);
+            status
+        }
+        "export" => builtin_export(shell, args, stdout_redirect, stderr_redirect),
+        "unset" => builtin_unset(shell, args, stderr_redirect),
+        "readonly" => builtin_readonly(shell, args, stdout_redirect, stderr_redirect),
+        "declare" | "typeset" => builtin_declare(shell, cmd, args, stdout_redirect, stderr_redirect),
+        "set" => builtin_set(shell, args, stdout_redirect, stderr_redirect),
+        "trap" => builtin_trap(shell, args, stdout_redirect, stderr_redirect),
+        "test" | "[" => builtin_test(shell, cmd, args, stderr_redirect),
+        "read" => builtin_read(shell, args, stderr_redirect),
+        "printf" => builtin_printf(shell, args, stdout_redirect, stderr_redirect),
+        "pushd" => builtin_pushd(shell, args, stdout_redirect, stderr_redirect),
+        "popd" => builtin_popd(shell, args, stdout_redirect, stderr_redirect),
+        "dirs" => builtin_dirs(shell, args, stdout_redirect, stderr_redirect),
+        _ => {
+            if let Some(err_file) = stderr_redirect {
+                let _ = writeln!(err_file, "{}: builtin not implemented", cmd);
+            } else {
+                eprintln!("{}: builtin not implemented", cmd);
+            }
+            1
+        }
+    }
+}
+
+/// Where a builtin's standard output goes: the redirected file if there is
+/// one, otherwise the shell's own stdout.
+fn output_stream(redirect: Option<&mut File>) -> Box<dyn Write + '_> {
+    match redirect {
+        Some(file) => Box::new(file),
+        None => Box::new(std::io::stdout()),
+    }
+}
+
+fn error_stream(redirect: Option<&mut File>) -> Box<dyn Write + '_> {
+    match redirect {
+        Some(file) => Box::new(file),
+        None => Box::new(std::io::stderr()),
+    }
+}
+
+/// Splits leading single-letter options off a builtin's arguments, stopping
+/// at `--` or the first operand. Returns the flags seen and the operands, or
+/// the first flag not in `allowed`.
+fn parse_flags<'a>(args: &'a [&'a str], allowed: &str) -> Result<(String, &'a [&'a str]), char> {
+    let mut flags = String::new();
+    let mut i = 0;
     while i < args.len() {
-        if args[i] == "2>" || args[i] == "2>>" {
-            if i + 1 < args.len() {
-                stderr_file = Some(args[i + 1].clone());
-                append = args[i] == "2>>";
-                args.drain(i..=i + 1);
+        let arg = args[i];
+        if arg == "--" {
+            i += 1;
+            break;
+        }
+        if !arg.starts_with('-') || arg.len() == 1 {
+            break;
+        }
+        for flag in arg[1..].chars() {
+            if !allowed.contains(flag) {
+                return Err(flag);
+            }
+            flags.push(flag);
+        }
+        i += 1;
+    }
+    Ok((flags, &args[i..]))
+}
+
+fn builtin_export(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    let (flags, names) = match parse_flags(args, "np") {
+        Ok(parsed) => parsed,
+        Err(flag) => {
+            let _ = writeln!(err, "export: -{}: invalid option", flag);
+            let _ = writeln!(err, "export: usage: export [-n] [-p] [name[=value] ...]");
+            return 2;
+        }
+    };
+
+    if names.is_empty() {
+        let mut out = output_stream(stdout_redirect);
+        for (name, var) in shell.vars.iter().filter(|(_, var)| var.exported) {
+            let _ = writeln!(out, "{}", declaration(name, var));
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in names {
+        let Some((name, assignment)) = declaration_target(arg) else {
+            let _ = writeln!(err, "export: `{}': not a valid identifier", arg);
+            status = 1;
+            continue;
+        };
+        if let Some(assignment) = assignment {
+            if let Err(e) = assign(shell, &assignment, false) {
+                let _ = writeln!(err, "export: {}", e);
+                status = 1;
+                continue;
+            }
+        }
+        shell.vars.set_exported(name, !flags.contains('n'));
+    }
+    status
+}
+
+/// Splits an argument to `export`, `readonly` or `declare` into the name
+/// it declares and the assignment it makes, if any. Returns `None` when the
+/// name is not a valid identifier.
+fn declaration_target(arg: &str) -> Option<(&str, Option<Assignment<'_>>)> {
+    match parse_assignment(arg) {
+        Some(assignment) => Some((assignment.name, Some(assignment))),
+        None if is_valid_name(arg) => Some((arg, None)),
+        None => None,
+    }
+}
+
+fn builtin_unset(shell: &mut Shell, args: &[&str], stderr_redirect: Option<&mut File>) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    let names = match parse_flags(args, "v") {
+        Ok((_, names)) => names,
+        Err(flag) => {
+            let _ = writeln!(err, "unset: -{}: invalid option", flag);
+            let _ = writeln!(err, "unset: usage: unset [-v] [name ...]");
+            return 2;
+        }
+    };
+
+    let mut status = 0;
+    for arg in names {
+        // `unset 'a[1]'` removes one element; a malformed subscript leaves
+        // an empty name, which is reported as invalid.
+        let (name, subscript) = match arg.split_once('[') {
+            Some((name, rest)) => match rest.strip_suffix(']') {
+                Some(subscript) => (name, Some(subscript)),
+                None => ("", None),
+            },
+            None => (*arg, None),
+        };
+        if !is_valid_name(name) {
+            let _ = writeln!(err, "unset: `{}': not a valid identifier", arg);
+            status = 1;
+            continue;
+        }
+        let result = match subscript {
+            Some(subscript) => shell.vars.unset_element(name, subscript),
+            None => shell.vars.unset(name),
+        };
+        if let Err(e) = result {
+            let _ = writeln!(err, "unset: {}", e);
+            status = 1;
+        }
+    }
+    status
+}
+
+fn builtin_readonly(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    let names = match parse_flags(args, "p") {
+        Ok((_, names)) => names,
+        Err(flag) => {
+            let _ = writeln!(err, "readonly: -{}: invalid option", flag);
+            let _ = writeln!(err, "readonly: usage: readonly [-p] [name[=value] ...]");
+            return 2;
+        }
+    };
+
+    if names.is_empty() {
+        let mut out = output_stream(stdout_redirect);
+        for (name, var) in shell.vars.iter().filter(|(_, var)| var.readonly) {
+            let _ = writeln!(out, "{}", declaration(name, var));
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in names {
+        let Some((name, assignment)) = declaration_target(arg) else {
+            let _ = writeln!(err, "readonly: `{}': not a valid identifier", arg);
+            status = 1;
+            continue;
+        };
+        if let Some(assignment) = assignment {
+            if let Err(e) = assign(shell, &assignment, false) {
+                let _ = writeln!(err, "readonly: {}", e);
+                status = 1;
+                continue;
+            }
+        }
+        shell.vars.declare(name).readonly = true;
+    }
+    status
+}
+
+/// `declare`/`typeset`: sets (`-x`) or clears (`+x`) attributes on each
+/// name, assigns any value given after the attributes are in place, and
+/// marks the variable readonly last so `declare -r N=1` works.
+fn builtin_declare(
+    shell: &mut Shell,
+    cmd: &str,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    let mut on = String::new();
+    let mut off = String::new();
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        if *arg == "--" {
+            i += 1;
+            break;
+        }
+        let flags = match arg.chars().next() {
+            Some('-') if arg.len() > 1 => &mut on,
+            Some('+') if arg.len() > 1 => &mut off,
+            _ => break,
+        };
+        for flag in arg[1..].chars() {
+            if !"aAilprux".contains(flag) {
+                let _ = writeln!(err, "{}: {}{}: invalid option", cmd, &arg[..1], flag);
+                let _ = writeln!(err, "{}: usage: {} [-aAilprux] [name[=value] ...]", cmd, cmd);
+                return 2;
+            }
+            flags.push(flag);
+        }
+        i += 1;
+    }
+    let names = &args[i..];
+
+    if names.is_empty() {
+        let mut out = output_stream(stdout_redirect);
+        let wanted: String = on.chars().filter(|&flag| flag != 'p').collect();
+        for (name, var) in shell.vars.iter() {
+            let flags = var.flags();
+            if wanted.chars().all(|flag| flags.contains(flag)) {
+                let _ = writeln!(out, "{}", declaration(name, var));
+            }
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    if on.contains('p') {
+        let mut out = output_stream(stdout_redirect);
+        for name in names {
+            match shell.vars.lookup(name) {
+                Some(var) => {
+                    let _ = writeln!(out, "{}", declaration(name, var));
+                }
+                None => {
+                    let _ = writeln!(err, "{}: {}: not found", cmd, name);
+                    status = 1;
+                }
+            }
+        }
+        return status;
+    }
+
+    for arg in names {
+        let Some((name, assignment)) = declaration_target(arg) else {
+            let _ = writeln!(err, "{}: `{}': not a valid identifier", cmd, arg);
+            status = 1;
+            continue;
+        };
+
+        let var = shell.vars.declare(name);
+        let changes_value =
+            assignment.is_some() || on.contains(['a', 'A', 'i', 'l', 'u']) || off.contains(['i', 'l', 'r', 'u']);
+        if var.readonly && changes_value {
+            let _ = writeln!(err, "{}: {}: readonly variable", cmd, name);
+            status = 1;
+            continue;
+        }
+        for flag in on.chars() {
+            match flag {
+                'i' => var.integer = true,
+                'l' => (var.lowercase, var.uppercase) = (true, false),
+                'u' => (var.lowercase, var.uppercase) = (false, true),
+                'x' => var.exported = true,
+                _ => {}
+            }
+        }
+        for flag in off.chars() {
+            match flag {
+                'i' => var.integer = false,
+                'l' => var.lowercase = false,
+                'u' => var.uppercase = false,
+                'x' => var.exported = false,
+                _ => {}
+            }
+        }
+
+        if off.contains(['a', 'A']) {
+            let _ = writeln!(err, "{}: {}: cannot destroy array variables in this way", cmd, name);
+            status = 1;
+            continue;
+        }
+        if let Some(assoc) = on.rfind(['a', 'A']).map(|i| &on[i..i + 1] == "A") {
+            if let Err(e) = shell.vars.make_array(name, assoc) {
+                let _ = writeln!(err, "{}: {}", cmd, e);
+                status = 1;
+                continue;
+            }
+        }
+
+        if let Some(assignment) = assignment {
+            if let Err(e) = assign(shell, &assignment, false) {
+                let _ = writeln!(err, "{}: {}", cmd, e);
+                status = 1;
+                continue;
+            }
+        }
+        if on.contains('r') {
+            shell.vars.declare(name).readonly = true;
+        }
+    }
+    status
+}
+
+/// `set`: turns options on (`-e`, `-o errexit`) or off (`+e`, `+o errexit`)
+/// and makes any remaining arguments the new positional parameters. `-o` or
+/// `+o` alone lists the options; with no arguments at all every variable is
+/// printed.
+fn builtin_set(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut out = output_stream(stdout_redirect);
+    let mut err = error_stream(stderr_redirect);
+    if args.is_empty() {
+        for (name, var) in shell.vars.iter() {
+            if let Some(assignment) = assignment(name, var) {
+                let _ = writeln!(out, "{}", assignment);
+            }
+        }
+        return 0;
+    }
+
+    let mut positional = None;
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        i += 1;
+        let on = match (*arg, arg.chars().next()) {
+            ("--", _) => {
+                positional = Some(&args[i..]);
+                break;
+            }
+            // `set -` ends the options and turns off -v and -x.
+            ("-", _) => {
+                shell.options.verbose = false;
+                shell.options.xtrace = false;
+                positional = Some(&args[i..]).filter(|rest| !rest.is_empty());
+                break;
+            }
+            (_, Some('-')) => true,
+            (_, Some('+')) => false,
+            _ => {
+                positional = Some(&args[i - 1..]);
+                break;
+            }
+        };
+
+        for letter in arg[1..].chars() {
+            if letter != 'o' {
+                match Options::name_of(letter) {
+                    Some(name) => {
+                        shell.options.set(name, on);
+                    }
+                    None => {
+                        let letters: String = OPTIONS.iter().filter_map(|(_, letter)| *letter).collect();
+                        let _ = writeln!(err, "set: {}{}: invalid option", &arg[..1], letter);
+                        let _ = writeln!(err, "set: usage: set [-{}] [-o option-name] [--] [arg ...]", letters);
+                        return 2;
+                    }
+                }
                 continue;
             }
+
+            match args.get(i) {
+                Some(name) => {
+                    i += 1;
+                    if !shell.options.set(name, on) {
+                        let _ = writeln!(err, "set: {}: invalid option name", name);
+                        return 2;
+                    }
+                }
+                None => {
+                    for (name, _) in OPTIONS {
+                        let enabled = shell.options.get(name) == Some(true);
+                        if on {
+                            let _ = writeln!(out, "{:<15}\t{}", name, if enabled { "on" } else { "off" });
+                        } else {
+                            let _ = writeln!(out, "set {}o {}", if enabled { '-' } else { '+' }, name);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(args) = positional {
+        shell.positional = args.iter().map(|arg| arg.to_string()).collect();
+    }
+    0
+}
+
+/// The logical current directory: `$PWD` while it is an absolute path to
+/// the directory the shell is in, otherwise the physical path.
+fn current_directory(shell: &Shell) -> String {
+    let same_file = |a: &fs::Metadata, b: &fs::Metadata| a.dev() == b.dev() && a.ino() == b.ino();
+    match (shell.vars.get("PWD"), fs::metadata(".")) {
+        (Some(pwd), Ok(cwd)) if pwd.starts_with('/') && fs::metadata(pwd).is_ok_and(|m| same_file(&m, &cwd)) => {
+            pwd.to_string()
+        }
+        _ => physical_directory().unwrap_or_default(),
+    }
+}
+
+/// The current directory with every symlink resolved.
+fn physical_directory() -> std::io::Result<String> {
+    Ok(env::current_dir()?.to_string_lossy().into_owned())
+}
+
+/// Changes to `dir`, recording the directory left in `OLDPWD` and the new
+/// one in `PWD`. Unless `physical`, `dir` is resolved against the logical
+/// current directory, so `..` leads back out of a symlinked directory; the
+/// physical path is the fallback when that fails.
+fn change_directory(shell: &mut Shell, dir: &str, physical: bool) -> std::io::Result<()> {
+    let old = current_directory(shell);
+    let logical = if physical { None } else { logical_path(&old, dir) };
+    let new = match logical {
+        Some(path) if env::set_current_dir(&path).is_ok() => path,
+        _ => {
+            env::set_current_dir(dir)?;
+            physical_directory()?
+        }
+    };
+    let _ = shell.vars.set("OLDPWD", old);
+    let _ = shell.vars.set("PWD", new);
+    Ok(())
+}
+
+/// An I/O error as the C library words it, without Rust's `(os error N)`.
+fn os_error(err: &std::io::Error) -> String {
+    match err.raw_os_error() {
+        Some(code) => nix::errno::Errno::from_raw(code).desc().to_string(),
+        None => err.to_string(),
+    }
+}
+
+/// `cd [-L|-P] [dir]`: changes to `dir`, or to `$HOME` without one. A
+/// relative `dir` is looked for under each directory in `$CDPATH`, and the
+/// directory found there is printed, as is the one `cd -` returns to from
+/// `$OLDPWD`. `-P` resolves symlinks instead of keeping the logical path.
+fn builtin_cd(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    let (flags, operands) = match parse_flags(args, "LP") {
+        Ok(parsed) => parsed,
+        Err(flag) => {
+            let _ = writeln!(err, "cd: -{}: invalid option", flag);
+            let _ = writeln!(err, "cd: usage: cd [-L|-P] [dir]");
+            return 2;
+        }
+    };
+    let physical = flags.ends_with('P');
+    let target = match operands {
+        [] => match shell.vars.get("HOME") {
+            Some(home) => home.to_string(),
+            None => {
+                let _ = writeln!(err, "cd: HOME not set");
+                return 1;
+            }
+        },
+        [""] => return 0,
+        [dir] => dir.to_string(),
+        _ => {
+            let _ = writeln!(err, "cd: too many arguments");
+            return 1;
+        }
+    };
+    let (target_dir, print) = match target.as_str() {
+        "~" => (shell.vars.get("HOME").unwrap_or(".").to_string(), false),
+        "-" => match shell.vars.get("OLDPWD") {
+            Some(oldpwd) => (oldpwd.to_string(), true),
+            None => {
+                let _ = writeln!(err, "cd: OLDPWD not set");
+                return 1;
+            }
+        },
+        _ => match shell.vars.get("CDPATH").and_then(|cdpath| search_cdpath(cdpath, &target)) {
+            Some((found, print)) => (found, print),
+            None => (target.clone(), false),
+        },
+    };
+    if let Err(e) = change_directory(shell, &target_dir, physical) {
+        let _ = writeln!(err, "cd: {}: {}", target, os_error(&e));
+        return 1;
+    }
+    if print {
+        let _ = writeln!(output_stream(stdout_redirect), "{}", current_directory(shell));
+    }
+    0
+}
+
+/// `pwd [-LP]`: prints the logical current directory, or with `-P` the
+/// physical one.
+fn builtin_pwd(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    let flags = match parse_flags(args, "LP") {
+        Ok((flags, _)) => flags,
+        Err(flag) => {
+            let _ = writeln!(err, "pwd: -{}: invalid option", flag);
+            let _ = writeln!(err, "pwd: usage: pwd [-LP]");
+            return 2;
+        }
+    };
+    let dir = if flags.ends_with('P') { physical_directory() } else { Ok(current_directory(shell)) };
+    match dir {
+        Ok(dir) => {
+            let _ = writeln!(output_stream(stdout_redirect), "{}", dir);
+            0
+        }
+        Err(e) => {
+            let _ = writeln!(err, "pwd: {}", os_error(&e));
+            1
+        }
+    }
+}
+
+/// Prints the directory stack the way `dirs` does by default, after a
+/// `pushd` or `popd` that changed it.
+fn print_dir_stack(shell: &Shell, out: &mut dyn Write) {
+    let home = shell.vars.get("HOME");
+    let entries: Vec<String> = shell
+        .dir_stack
+        .entries(&current_directory(shell))
+        .iter()
+        .map(|dir| abbreviate(dir, home))
+        .collect();
+    let _ = writeln!(out, "{}", entries.join(" "));
+}
+
+/// `pushd [-n] [+N | -N | dir]`: saves the current directory and changes to
+/// `dir`, or rotates the stack so entry `N` is on top, or with no operand
+/// swaps the top two. `-n` adds `dir` to the stack without changing to it.
+fn builtin_pushd(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    let (no_change, operands) = match args {
+        ["-n", rest @ ..] => (true, rest),
+        ["--", rest @ ..] => (false, rest),
+        _ => (false, args),
+    };
+    let pwd = current_directory(shell);
+    match operands {
+        [] if no_change => return 0,
+        [] => {
+            let mut entries = shell.dir_stack.entries(&pwd);
+            if entries.len() < 2 {
+                let _ = writeln!(err, "pushd: no other directory");
+                return 1;
+            }
+            entries.swap(0, 1);
+            if let Err(e) = change_directory(shell, &entries[0], false) {
+                let _ = writeln!(err, "pushd: {}: {}", entries[0], os_error(&e));
+                return 1;
+            }
+            shell.dir_stack.replace(entries);
+        }
+        [arg] => match Offset::parse(arg) {
+            Some(Err(())) => {
+                let _ = writeln!(err, "pushd: {}: invalid number", arg);
+                let _ = writeln!(err, "pushd: usage: pushd [-n] [+N | -N | dir]");
+                return 2;
+            }
+            Some(Ok(_)) if shell.dir_stack.is_empty() => {
+                let _ = writeln!(err, "pushd: directory stack empty");
+                return 1;
+            }
+            Some(Ok(offset)) => {
+                let Some(index) = shell.dir_stack.index(offset) else {
+                    let _ = writeln!(err, "pushd: {}: directory stack index out of range", arg);
+                    return 1;
+                };
+                let mut entries = shell.dir_stack.entries(&pwd);
+                entries.rotate_left(index);
+                if let Err(e) = change_directory(shell, &entries[0], false) {
+                    let _ = writeln!(err, "pushd: {}: {}", entries[0], os_error(&e));
+                    return 1;
+                }
+                shell.dir_stack.replace(entries);
+            }
+            None if no_change => shell.dir_stack.push(arg.to_string()),
+            None => {
+                if let Err(e) = change_directory(shell, arg, false) {
+                    let _ = writeln!(err, "pushd: {}: {}", arg, os_error(&e));
+                    return 1;
+                }
+                shell.dir_stack.push(pwd);
+            }
+        },
+        _ => {
+            let _ = writeln!(err, "pushd: too many arguments");
+            return 1;
         }
-        i += 1;
     }
+    print_dir_stack(shell, &mut output_stream(stdout_redirect));
+    0
+}
 
-    (stderr_file, append)
+/// `popd [-n] [+N | -N]`: drops the top of the stack and changes to the
+/// directory beneath it, or drops entry `N`. `-n` drops the entry beneath
+/// the current directory without changing to it.
+fn builtin_popd(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    let (no_change, operands) = match args {
+        ["-n", rest @ ..] => (true, rest),
+        ["--", rest @ ..] => (false, rest),
+        _ => (false, args),
+    };
+    let index = match operands {
+        [] => Some(usize::from(no_change)),
+        [arg] => match Offset::parse(arg) {
+            Some(Ok(offset)) => shell.dir_stack.index(offset),
+            Some(Err(())) => {
+                let _ = writeln!(err, "popd: {}: invalid number", arg);
+                let _ = writeln!(err, "popd: usage: popd [-n] [+N | -N]");
+                return 2;
+            }
+            None => {
+                let _ = writeln!(err, "popd: {}: invalid argument", arg);
+                let _ = writeln!(err, "popd: usage: popd [-n] [+N | -N]");
+                return 2;
+            }
+        },
+        _ => {
+            let _ = writeln!(err, "popd: too many arguments");
+            return 1;
+        }
+    };
+    if shell.dir_stack.is_empty() {
+        let _ = writeln!(err, "popd: directory stack empty");
+        return 1;
+    }
+    let Some(index) = index else {
+        let _ = writeln!(err, "popd: {}: directory stack index out of range", operands[0]);
+        return 1;
+    };
+    if index == 0 {
+        let mut entries = shell.dir_stack.entries(&current_directory(shell));
+        entries.remove(0);
+        if let Err(e) = change_directory(shell, &entries[0], false) {
+            let _ = writeln!(err, "popd: {}: {}", entries[0], os_error(&e));
+            return 1;
+        }
+        shell.dir_stack.replace(entries);
+    } else {
+        shell.dir_stack.remove(index);
+    }
+    print_dir_stack(shell, &mut output_stream(stdout_redirect));
+    0
 }
 
-fn check_executable(path: &Path, arg: &str) -> bool {
-    if path.exists()
-        && path.is_file()
-        && path.metadata().map(|m| m.permissions().mode() & 0o111 != 0).unwrap_or(false)
-    {
-        println!("{} is {}", arg, path.display());
-        true
+/// `dirs [-clpv] [+N | -N]`: lists the directory stack, current directory
+/// first, with `$HOME` shown as `~` unless `-l` is given. `-p` prints one
+/// entry per line and `-v` numbers them; `-c` empties the stack.
+fn builtin_dirs(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut out = output_stream(stdout_redirect);
+    let mut err = error_stream(stderr_redirect);
+    let mut flags = String::new();
+    let mut offset = None;
+    for arg in args {
+        match Offset::parse(arg) {
+            _ if *arg == "--" => {}
+            Some(Ok(parsed)) => offset = Some((arg, parsed)),
+            Some(Err(())) if arg.starts_with('-') && arg[1..].chars().all(|flag| "clpv".contains(flag)) => {
+                flags.push_str(&arg[1..]);
+            }
+            parsed => {
+                let problem = if parsed.is_some() { "invalid number" } else { "invalid option" };
+                let _ = writeln!(err, "dirs: {}: {}", arg, problem);
+                let _ = writeln!(err, "dirs: usage: dirs [-clpv] [+N] [-N]");
+                return 2;
+            }
+        }
+    }
+    if flags.contains('c') {
+        shell.dir_stack.clear();
+        return 0;
+    }
+
+    let home = if flags.contains('l') { None } else { shell.vars.get("HOME") };
+    let entries = shell.dir_stack.entries(&current_directory(shell));
+    let mut shown: Vec<(usize, String)> =
+        entries.iter().enumerate().map(|(i, dir)| (i, abbreviate(dir, home))).collect();
+    if let Some((arg, offset)) = offset {
+        let Some(index) = shell.dir_stack.index(offset) else {
+            let _ = writeln!(err, "dirs: {}: directory stack index out of range", &arg[1..]);
+            return 1;
+        };
+        shown = vec![shown.swap_remove(index)];
+    }
+    if flags.contains('v') {
+        for (i, dir) in &shown {
+            let _ = writeln!(out, "{:2}  {}", i, dir);
+        }
+    } else if flags.contains('p') {
+        for (_, dir) in &shown {
+            let _ = writeln!(out, "{}", dir);
+        }
     } else {
-        false
+        let dirs: Vec<&str> = shown.iter().map(|(_, dir)| dir.as_str()).collect();
+        let _ = writeln!(out, "{}", dirs.join(" "));
     }
+    0
 }
 
-fn is_builtin(cmd: &str) -> bool {
-    matches!(cmd, "cd" | "pwd" | "echo" | "exit" | "type")
+/// How `type` and `command -v`/`-V` describe what a name resolves to.
+struct Describe {
+    /// `type -a`: every match rather than the first.
+    all: bool,
+    /// `type -t`: one word per match.
+    terse: bool,
+    /// `type -p`: only the path of a file.
+    paths: bool,
+    /// `type -P`: files only, searched for even when a builtin matches.
+    files_only: bool,
+    /// `command -v`: the path of a file, or a keyword or builtin's name.
+    names: bool,
 }
 
-fn run_builtin(
-    cmd: &str,
+impl Describe {
+    /// Writes what `name` resolves to and returns whether it was found.
+    fn describe(&self, shell: &Shell, name: &str, out: &mut dyn Write) -> bool {
+        let mut found = lookup::resolve(name, shell.vars.get("PATH"), self.all || self.files_only);
+        let hashed = shell.hash.get(name).map(|hashed| &hashed.path);
+        if self.files_only {
+            found.retain(|command| matches!(command, lookup::Command::File(_)));
+            if !self.all {
+                found.truncate(1);
+            }
+        }
+        for command in &found {
+            let _ = match (command, self.terse) {
+                (lookup::Command::Keyword, true) => writeln!(out, "keyword"),
+                (lookup::Command::Builtin, true) => writeln!(out, "builtin"),
+                (lookup::Command::File(_), true) => writeln!(out, "file"),
+                (lookup::Command::File(path), _) if self.paths || self.names => writeln!(out, "{}", path.display()),
+                (_, _) if self.names => writeln!(out, "{}", name),
+                (_, _) if self.paths => Ok(()),
+                (lookup::Command::Keyword, _) => writeln!(out, "{} is a shell keyword", name),
+                (lookup::Command::Builtin, _) => writeln!(out, "{} is a shell builtin", name),
+                (lookup::Command::File(path), _) if hashed == Some(path) => {
+                    writeln!(out, "{} is hashed ({})", name, path.display())
+                }
+                (lookup::Command::File(path), _) => writeln!(out, "{} is {}", name, path.display()),
+            };
+        }
+        !found.is_empty()
+    }
+}
+
+/// `type [-afptP] name ...`: says what each name would run as. `-a` lists
+/// every match, `-t` prints one word (`keyword`, `builtin` or `file`), `-p`
+/// prints only the path of a file, and `-P` searches `PATH` even for
+/// builtins. `-f` is accepted; there are no functions to skip.
+fn builtin_type(
+    shell: &mut Shell,
     args: &[&str],
     stdout_redirect: Option<&mut File>,
     stderr_redirect: Option<&mut File>,
-) {
-    match cmd {
-        "echo" => {
-            let output = args.join(" ");
-            if let Some(out) = stdout_redirect {
-                let _ = writeln!(out, "{}", output);
+) -> i32 {
+    let mut out = output_stream(stdout_redirect);
+    let mut err = error_stream(stderr_redirect);
+    let (flags, names) = match parse_flags(args, "afptP") {
+        Ok(parsed) => parsed,
+        Err(flag) => {
+            let _ = writeln!(err, "type: -{}: invalid option", flag);
+            let _ = writeln!(err, "type: usage: type [-afptP] name [name ...]");
+            return 2;
+        }
+    };
+    let describe = Describe {
+        all: flags.contains('a'),
+        terse: flags.contains('t'),
+        paths: flags.contains(['p', 'P']),
+        files_only: flags.contains('P'),
+        names: false,
+    };
+    let mut status = 0;
+    for name in names {
+        if !describe.describe(shell, name, &mut out) {
+            if !(describe.terse || describe.paths || describe.files_only) {
+                let _ = writeln!(err, "{}: not found", name);
+            }
+            status = 1;
+        }
+    }
+    status
+}
+
+/// `hash [-lr] [-p path] [-dt] [name ...]`: with no names, lists the
+/// remembered command locations and how often each was used, or with `-l`
+/// as commands that recreate them. Names are looked up and remembered, or
+/// with `-p` remembered at `path`; `-d` forgets them, `-t` prints where they
+/// are, and `-r` empties the table.
+fn builtin_hash(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut out = output_stream(stdout_redirect);
+    let mut err = error_stream(stderr_redirect);
+    let mut flags = String::new();
+    let mut pathname = None;
+    let mut rest = args;
+    while let Some((arg, tail)) = rest.split_first().filter(|(arg, _)| arg.starts_with('-') && arg.len() > 1) {
+        rest = tail;
+        if *arg == "--" {
+            break;
+        }
+        for flag in arg[1..].chars() {
+            if !"dlprt".contains(flag) {
+                let _ = writeln!(err, "hash: -{}: invalid option", flag);
+                let _ = writeln!(err, "hash: usage: hash [-lr] [-p pathname] [-dt] [name ...]");
+                return 2;
+            }
+            if flag == 'p' {
+                let Some((path, tail)) = rest.split_first() else {
+                    let _ = writeln!(err, "hash: -p: option requires an argument");
+                    return 1;
+                };
+                pathname = Some(*path);
+                rest = tail;
+            }
+            flags.push(flag);
+        }
+    }
+    let names = rest;
+    let path_var = shell.vars.get("PATH").map(str::to_string);
+    shell.hash.sync(path_var.as_deref());
+
+    if flags.contains('r') {
+        shell.hash.clear();
+    }
+    if names.is_empty() {
+        if flags.contains(['d', 't']) {
+            let flag = if flags.contains('d') { 'd' } else { 't' };
+            let _ = writeln!(err, "hash: -{}: option requires an argument", flag);
+            return 1;
+        }
+        if flags.contains('r') {
+            return 0;
+        }
+        if shell.hash.is_empty() {
+            let _ = writeln!(out, "hash: hash table empty");
+            return 0;
+        }
+        if !flags.contains('l') {
+            let _ = writeln!(out, "hits\tcommand");
+        }
+        for (name, hashed) in shell.hash.iter() {
+            let _ = if flags.contains('l') {
+                writeln!(out, "builtin hash -p {} {}", hashed.path.display(), name)
             } else {
-                println!("{}", output);
+                writeln!(out, "{:4}\t{}", hashed.hits, hashed.path.display())
+            };
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for name in names {
+        let found = if let Some(path) = pathname {
+            shell.hash.insert(name, PathBuf::from(path));
+            true
+        } else if flags.contains('d') {
+            shell.hash.remove(name)
+        } else if flags.contains('t') {
+            match shell.hash.get(name) {
+                Some(hashed) if names.len() > 1 => writeln!(out, "{}\t{}", name, hashed.path.display()).is_ok(),
+                Some(hashed) => writeln!(out, "{}", hashed.path.display()).is_ok(),
+                None => false,
             }
+        } else {
+            is_builtin(name) || shell.hash.add(name, path_var.as_deref())
+        };
+        if !found {
+            let _ = writeln!(err, "hash: {}: not found", name);
+            status = 1;
         }
-        "pwd" => {
-            match env::current_dir() {
-                Ok(path) => {
-                    if let Some(out) = stdout_redirect {
-                        let _ = writeln!(out, "{}", path.display());
-                    } else {
-                        println!("{}", path.display());
+    }
+    status
+}
+
+/// `wait [pid ...]` waits for the given background jobs, or for all of them,
+/// and returns the status of the last one named.
+fn builtin_wait(shell: &mut Shell, args: &[&str], stderr_redirect: Option<&mut File>) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    if args.is_empty() {
+        for job in std::mem::take(&mut shell.jobs) {
+            wait_for(job);
+        }
+        return 0;
+    }
+
+    let mut status = 0;
+    for arg in args {
+        let Ok(pid) = arg.parse::<i32>() else {
+            let _ = writeln!(err, "wait: `{}': not a pid or valid job spec", arg);
+            status = 1;
+            continue;
+        };
+        match shell.jobs.iter().position(|job| job.as_raw() == pid) {
+            Some(index) => status = wait_for(shell.jobs.remove(index)),
+            None => {
+                let _ = writeln!(err, "wait: pid {} is not a child of this shell", pid);
+                status = 127;
+            }
+        }
+    }
+    status
+}
+
+/// `history [n]` lists the history, or its last `n` entries. `-c` clears it
+/// and `-d offset` or `-d start-end` deletes entries. `-a`, `-n`, `-r` and
+/// `-w` append to, read new lines from, read or write the history file,
+/// `$HISTFILE` unless one is named. `-s` stores its arguments as an entry
+/// and `-p` prints their history expansion; both replace the `history`
+/// command itself.
+fn builtin_history(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut out = output_stream(stdout_redirect);
+    let mut err = error_stream(stderr_redirect);
+    let usage = "history: usage: history [-c] [-d offset] [n] or history -anrw [filename] or history -ps arg [arg...]";
+    let mut flags = String::new();
+    let mut offset = None;
+    let mut rest = args;
+    while let Some((arg, tail)) = rest.split_first().filter(|(arg, _)| arg.starts_with('-') && arg.len() > 1) {
+        rest = tail;
+        if *arg == "--" {
+            break;
+        }
+        for flag in arg[1..].chars() {
+            if !"acdnprsw".contains(flag) {
+                let _ = writeln!(err, "history: -{}: invalid option", flag);
+                let _ = writeln!(err, "{}", usage);
+                return 2;
+            }
+            if flag == 'd' {
+                let Some((arg, tail)) = rest.split_first() else {
+                    let _ = writeln!(err, "history: -d: option requires an argument");
+                    let _ = writeln!(err, "{}", usage);
+                    return 2;
+                };
+                offset = Some(*arg);
+                rest = tail;
+            }
+            flags.push(flag);
+        }
+    }
+    let operands = rest;
+
+    if flags.contains('c') {
+        shell.history.clear();
+    }
+    if let Some(offset) = offset {
+        // A `-` after the first character separates the ends of a range.
+        let (start, end) = match offset[1..].find('-') {
+            Some(dash) => offset.split_at(dash + 1),
+            None => (offset, "-"),
+        };
+        let index = |n: &str| n.parse().ok().and_then(|n| shell.history.index(n));
+        let range = match (index(start), end) {
+            (Some(first), "-") => Some(first..first + 1),
+            (Some(first), end) => index(&end[1..]).filter(|&last| last >= first).map(|last| first..last + 1),
+            (None, _) => None,
+        };
+        let Some(range) = range else {
+            let _ = writeln!(err, "history: {}: history position out of range", offset);
+            return 1;
+        };
+        shell.history.delete(range);
+    }
+
+    let file_flags: Vec<char> = flags.chars().filter(|flag| "anrw".contains(*flag)).collect();
+    if file_flags.len() > 1 {
+        let _ = writeln!(err, "history: cannot use more than one of -anrw");
+        return 1;
+    }
+    if let Some(&flag) = file_flags.first() {
+        let Some(path) = operands.first().map(PathBuf::from).or_else(|| history_file(shell)) else {
+            return 0;
+        };
+        let limit = history::limit(shell.vars.get("HISTSIZE"));
+        let result = match flag {
+            'a' => shell.history.append_new(&path),
+            'n' => shell.history.load_new(&path, limit),
+            'r' => shell.history.load(&path, limit),
+            _ => shell.history.save(&path, history::limit(shell.vars.get("HISTFILESIZE"))),
+        };
+        return match result {
+            Ok(()) => 0,
+            Err(e) => {
+                let _ = writeln!(err, "history: {}: {}", path.display(), os_error(&e));
+                1
+            }
+        };
+    }
+
+    if flags.contains(['s', 'p']) {
+        if shell.interactive {
+            shell.history.pop();
+        }
+        if flags.contains('s') {
+            if !operands.is_empty() {
+                let limit = history::limit(shell.vars.get("HISTSIZE"));
+                shell.history.push(&operands.join(" "), limit);
+            }
+        } else {
+            for operand in operands {
+                match shell.history.expand(operand) {
+                    Ok(expansion) => {
+                        let _ = writeln!(out, "{}", expansion.line);
                     }
-                }
-                Err(err) => {
-                    if let Some(err_file) = stderr_redirect {
-                        let _ = writeln!(err_file, "pwd: {}", err);
-                    } else {
-                        eprintln!("pwd: {}", err);
+                    Err(e) => {
+                        let _ = writeln!(err, "history: {}", e);
+                        return 1;
                     }
                 }
             }
         }
-        "cd" => {
-            let target = args.get(0).map(|s| *s).unwrap_or("");
-            let target_dir = if target == "~" {
-                env::var("HOME").unwrap_or_else(|_| ".".into())
-            } else {
-                target.to_string()
-            };
-            if let Err(err) = env::set_current_dir(&target_dir) {
-                if let Some(err_file) = stderr_redirect {
-                    let _ = writeln!(err_file, "cd: {}: {}", target_dir, err);
-                } else {
-                    eprintln!("cd: {}: {}", target_dir, err);
+        return 0;
+    }
+    if !flags.is_empty() {
+        return 0;
+    }
+
+    let count = match operands {
+        [] => None,
+        [n] => match n.parse::<usize>() {
+            Ok(n) => Some(n),
+            Err(_) => {
+                let _ = writeln!(err, "history: {}: numeric argument required", n);
+                return 1;
+            }
+        },
+        _ => {
+            let _ = writeln!(err, "history: too many arguments");
+            return 2;
+        }
+    };
+    let entries = shell.history.entries();
+    let start = count.map_or(0, |n| entries.len().saturating_sub(n));
+    for (i, entry) in entries.iter().enumerate().skip(start) {
+        let _ = writeln!(out, "{:5}  {}", shell.history.number(i), entry);
+    }
+    0
+}
+
+/// `command -v name ...` prints the path or name each would run as, and
+/// `command -V name ...` describes them as `type` does. Running a command
+/// through `command [-p] name args` is handled before builtins are reached,
+/// by `lookup_prefix`.
+fn builtin_command(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut out = output_stream(stdout_redirect);
+    let mut err = error_stream(stderr_redirect);
+    let (flags, names) = match parse_flags(args, "pvV") {
+        Ok(parsed) => parsed,
+        Err(flag) => {
+            let _ = writeln!(err, "command: -{}: invalid option", flag);
+            let _ = writeln!(err, "command: usage: command [-pVv] command [arg ...]");
+            return 2;
+        }
+    };
+    if !flags.contains(['v', 'V']) {
+        let words = names.iter().map(|name| name.to_string()).collect();
+        return if names.is_empty() { 0 } else { run_words(shell, words) };
+    }
+    let verbose = flags.ends_with('V') || !flags.contains('v');
+    let describe = Describe { all: false, terse: false, paths: false, files_only: false, names: !verbose };
+    let mut found = false;
+    for name in names {
+        if describe.describe(shell, name, &mut out) {
+            found = true;
+        } else if verbose {
+            let _ = writeln!(err, "command: {}: not found", name);
+        }
+    }
+    if found { 0 } else { 1 }
+}
+
+/// `echo [-neE] [arg ...]`: prints the arguments separated by spaces. `-n`
+/// drops the trailing newline, and `-e` interprets backslash escapes, where
+/// `\c` ends the output. Under `set -o xpg_echo` it behaves as XSI
+/// specifies: every argument is printed and escapes are always interpreted.
+fn builtin_echo(shell: &Shell, args: &[&str], stdout_redirect: Option<&mut File>) -> i32 {
+    let mut newline = true;
+    let mut escapes = shell.options.xpg_echo;
+    let mut operands = args;
+    while let Some((arg, rest)) = operands.split_first().filter(|_| !shell.options.xpg_echo) {
+        let Some(flags) = arg
+            .strip_prefix('-')
+            .filter(|flags| !flags.is_empty() && flags.chars().all(|flag| "neE".contains(flag)))
+        else {
+            break;
+        };
+        for flag in flags.chars() {
+            match flag {
+                'n' => newline = false,
+                'e' => escapes = true,
+                _ => escapes = false,
+            }
+        }
+        operands = rest;
+    }
+
+    let mut output = String::new();
+    'words: for (i, arg) in operands.iter().enumerate() {
+        if i > 0 {
+            output.push(' ');
+        }
+        if !escapes {
+            output.push_str(arg);
+            continue;
+        }
+        let mut chars = arg.chars().peekable();
+        while let Some(ch) = chars.next() {
+            if ch != '\\' {
+                output.push(ch);
+                continue;
+            }
+            match printf::escape(&mut chars, true) {
+                Escape::Char(text) => output.push_str(&text),
+                Escape::Stop => {
+                    newline = false;
+                    break 'words;
                 }
             }
         }
-        "exit" => {
-            process::exit(0);
+    }
+    if newline {
+        output.push('\n');
+    }
+    let mut out = output_stream(stdout_redirect);
+    let _ = out.write_all(output.as_bytes());
+    let _ = out.flush();
+    0
+}
+
+/// `test expr` and `[ expr ]`: exit 0 if the conditional expression is
+/// true, 1 if it is false and 2 on an error.
+fn builtin_test(shell: &Shell, cmd: &str, args: &[&str], stderr_redirect: Option<&mut File>) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    let args = match (cmd, args.split_last()) {
+        ("[", Some((&"]", operands))) => operands,
+        ("[", _) => {
+            let _ = writeln!(err, "[: missing `]'");
+            return 2;
         }
-        "type" => {
-            if let Some(arg) = args.first() {
-                if is_builtin(arg) {
-                    println!("{} is a shell builtin", arg);
-                } else {
-                    let mut found = false;
-                    if let Ok(path_var) = env::var("PATH") {
-                        found = path_var.split(':').any(|dir| {
-                            let full_path = Path::new(dir).join(arg);
-                            full_path.exists() && full_path.is_file()
-                                && full_path.metadata().map(|m| m.permissions().mode() & 0o111 != 0).unwrap_or(false)
-                        });
-                    }
+        _ => args,
+    };
+    match cond::test(shell, args) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            let _ = writeln!(err, "{}: {}", cmd, e);
+            2
+        }
+    }
+}
 
-                    if found {
-                        println!("{} is a binary in PATH", arg);
-                    } else {
-                        println!("{}: not found", arg);
+/// `read [-rs] [-a array] [-d delim] [-n nchars] [-p prompt] [-t timeout]
+/// [name ...]`: reads a line from stdin and assigns its fields to the
+/// names, the last taking the rest of the line, or the whole line to
+/// `REPLY` without names. Exits 1 at end of file and above 128 on timeout.
+fn builtin_read(shell: &mut Shell, args: &[&str], stderr_redirect: Option<&mut File>) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    let usage = "read: usage: read [-rs] [-a array] [-d delim] [-n nchars] [-p prompt] [-t timeout] [name ...]";
+    let mut options = ReadOptions {
+        raw: false,
+        delimiter: b'\n',
+        count: None,
+        timeout: None,
+        silent: false,
+        buffered: shell.input_buffered,
+    };
+    let mut array = None;
+    let mut prompt = None;
+
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        if *arg == "--" {
+            i += 1;
+            break;
+        }
+        if !arg.starts_with('-') || arg.len() == 1 {
+            break;
+        }
+        i += 1;
+        for (j, flag) in arg.char_indices().skip(1) {
+            match flag {
+                'r' => options.raw = true,
+                's' => options.silent = true,
+                'a' | 'd' | 'n' | 'p' | 't' => {
+                    // The value is the rest of this argument or the next one.
+                    let value = match &arg[j + 1..] {
+                        "" => match args.get(i) {
+                            Some(value) => {
+                                i += 1;
+                                *value
+                            }
+                            None => {
+                                let _ = writeln!(err, "read: -{}: option requires an argument", flag);
+                                let _ = writeln!(err, "{}", usage);
+                                return 2;
+                            }
+                        },
+                        rest => rest,
+                    };
+                    match flag {
+                        'a' => array = Some(value),
+                        'd' => options.delimiter = value.bytes().next().unwrap_or(0),
+                        'p' => prompt = Some(value),
+                        'n' => match value.parse() {
+                            Ok(count) => options.count = Some(count),
+                            Err(_) => {
+                                let _ = writeln!(err, "read: {}: invalid number", value);
+                                return 2;
+                            }
+                        },
+                        _ => match value.parse::<f64>().ok().and_then(|secs| Duration::try_from_secs_f64(secs).ok()) {
+                            Some(timeout) => options.timeout = Some(timeout),
+                            None => {
+                                let _ = writeln!(err, "read: {}: invalid timeout specification", value);
+                                return 1;
+                            }
+                        },
                     }
+                    break;
+                }
+                _ => {
+                    let _ = writeln!(err, "read: -{}: invalid option", flag);
+                    let _ = writeln!(err, "{}", usage);
+                    return 2;
                 }
-            } else {
-                println!("type: missing argument");
             }
         }
-        _ => {
-            if let Some(err_file) = stderr_redirect {
-                let _ = writeln!(err_file, "{}: builtin not implemented", cmd);
-            } else {
-                eprintln!("{}: builtin not implemented", cmd);
+    }
+    let names = &args[i..];
+    if let Some(name) = array.iter().chain(names).find(|name| !is_valid_name(name)) {
+        let _ = writeln!(err, "read: `{}': not a valid identifier", name);
+        return 1;
+    }
+
+    if options.timeout == Some(Duration::ZERO) {
+        return if read::input_ready(Duration::ZERO) { 0 } else { 1 };
+    }
+    let terminal = unsafe { libc::isatty(libc::STDIN_FILENO) } == 1;
+    if let Some(prompt) = prompt.filter(|_| terminal) {
+        let _ = write!(err, "{}", prompt);
+        let _ = err.flush();
+    }
+    let (input, end) = match read::read_input(&options) {
+        Ok(result) => result,
+        Err(e) => {
+            let _ = writeln!(err, "read: read error: {}", e);
+            return 1;
+        }
+    };
+
+    let ifs = ifs(shell).to_string();
+    let result = if let Some(array) = array {
+        let fields = read::split_all(&input, &ifs);
+        shell.vars.set_array(array, fields.into_iter().map(|field| (None, field)).collect(), false).map(|_| vec![array])
+    } else if names.is_empty() {
+        shell.vars.set("REPLY", read::text(&input)).map(|_| vec!["REPLY"])
+    } else {
+        let fields = read::split_fields(&input, &ifs, names.len());
+        names
+            .iter()
+            .zip(fields)
+            .try_for_each(|(name, field)| shell.vars.set(name, field))
+            .map(|_| names.to_vec())
+    };
+    match result {
+        Ok(assigned) => {
+            for name in assigned {
+                after_assignment(shell, name);
             }
         }
+        Err(e) => {
+            let _ = writeln!(err, "read: {}", e);
+            return 1;
+        }
+    }
+
+    match end {
+        End::Complete => 0,
+        End::Eof => 1,
+        End::TimedOut => 128 + libc::SIGALRM,
     }
 }
 
-fn handle_pipeline(cmd_line: &str) {
-    let stages: Vec<&str> = cmd_line.split('|').map(str::trim).collect();
-    let num_cmds = stages.len();
+/// `printf [-v var] format [arguments]`: prints the arguments as `format`
+/// says, or with `-v` assigns the result to `var`.
+fn builtin_printf(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut err = error_stream(stderr_redirect);
+    let (target, args) = match args {
+        ["-v", target, rest @ ..] => (Some(*target), rest),
+        ["-v"] => {
+            let _ = writeln!(err, "printf: -v: option requires an argument");
+            return 2;
+        }
+        _ => (None, args),
+    };
+    let args = args.strip_prefix(&["--"]).unwrap_or(args);
+    let Some((format, args)) = args.split_first() else {
+        let _ = writeln!(err, "printf: usage: printf [-v var] format [arguments]");
+        return 2;
+    };
+
+    let formatted = printf::format(format, args);
+    for error in &formatted.errors {
+        let _ = writeln!(err, "printf: {}", error);
+    }
+    let status = if formatted.errors.is_empty() { 0 } else { 1 };
+
+    let Some(target) = target else {
+        let mut out = output_stream(stdout_redirect);
+        let _ = out.write_all(formatted.output.as_bytes());
+        let _ = out.flush();
+        return status;
+    };
+    let word = format!("{}=", target);
+    let Some(assignment) = parse_assignment(&word).filter(|assignment| !assignment.append) else {
+        let _ = writeln!(err, "printf: `{}': not a valid identifier", target);
+        return 2;
+    };
+    let result = match assignment.subscript {
+        Some(subscript) => shell.vars.set_element(assignment.name, subscript, formatted.output),
+        None => shell.vars.set(assignment.name, formatted.output),
+    };
+    match result {
+        Ok(()) => {
+            after_assignment(shell, assignment.name);
+            status
+        }
+        Err(e) => {
+            let _ = writeln!(err, "printf: {}", e);
+            1
+        }
+    }
+}
 
-    if num_cmds < 2 {
-        eprintln!("Pipeline must contain at least two commands.");
-        return;
+/// `trap [-lp] [[action] condition ...]`: with an action, sets it for each
+/// condition; with `-` or no action, resets them. `-p` or no arguments
+/// lists the traps in a form that can be read back in, and `-l` lists the
+/// signal names.
+fn builtin_trap(
+    shell: &mut Shell,
+    args: &[&str],
+    stdout_redirect: Option<&mut File>,
+    stderr_redirect: Option<&mut File>,
+) -> i32 {
+    let mut out = output_stream(stdout_redirect);
+    let mut err = error_stream(stderr_redirect);
+    let (flags, operands) = match parse_flags(args, "lp") {
+        Ok(parsed) => parsed,
+        Err(flag) => {
+            let _ = writeln!(err, "trap: -{}: invalid option", flag);
+            let _ = writeln!(err, "trap: usage: trap [-lp] [[arg] signal_spec ...]");
+            return 2;
+        }
+    };
+    if flags.contains('l') {
+        let _ = write!(out, "{}", trap::list());
+        return 0;
+    }
+
+    let mut status = 0;
+    let mut conditions = Vec::new();
+    // With `-p`, or a lone operand other than `-`, every operand is a
+    // condition; otherwise the first is the action.
+    let (action, specs) = match operands {
+        _ if flags.contains('p') || operands.is_empty() => (None, operands),
+        [spec] if *spec != "-" => (None, operands),
+        [action, specs @ ..] => (Some(*action), specs),
+        [] => unreachable!(),
+    };
+    // An action that is a number is a condition to reset too.
+    let (action, specs) = match action {
+        Some(action) if action.parse::<u32>().is_ok() => (None, operands),
+        _ => (action, specs),
+    };
+    for spec in specs {
+        match Condition::parse(spec) {
+            Some(condition) => conditions.push(condition),
+            None => {
+                let _ = writeln!(err, "trap: {}: invalid signal specification", spec);
+                status = 1;
+            }
+        }
+    }
+
+    if flags.contains('p') || operands.is_empty() {
+        for (condition, trap_action) in shell.traps.iter() {
+            if conditions.is_empty() || conditions.contains(&condition) {
+                let quoted = format!("'{}'", trap_action.replace('\'', "'\\''"));
+                let _ = writeln!(out, "trap -- {} {}", quoted, condition.name());
+            }
+        }
+        return status;
+    }
+
+    for condition in conditions {
+        match action {
+            Some(action) if action != "-" => shell.traps.set(condition, action),
+            _ => shell.traps.reset(condition),
+        }
     }
+    status
+}
+
+/// Runs the stages of a pipeline in child processes connected by pipes. The
+/// status is the last stage's, or with `set -o pipefail` the last non-zero
+/// one.
+fn handle_pipeline(shell: &mut Shell, stages: &[&str]) -> i32 {
+    let num_cmds = stages.len();
 
     // Prepare N–1 pipes
     let mut pipes = Vec::with_capacity(num_cmds - 1);
@@ -609,22 +3024,23 @@ fn handle_pipeline(cmd_line: &str) {
             Ok((r, w)) => pipes.push((r, w)),
             Err(err) => {
                 eprintln!("pipe failed: {}", err);
-                return;
+                return 1;
             }
         }
     }
 
     // Fork each stage
+    let mut children = Vec::with_capacity(num_cmds);
     for i in 0..num_cmds {
         let stage = stages[i];
-        let parts: Vec<String> = tokenize(stage);
-        let cmd_name = parts[0].as_str();
-        let args: Vec<&str> = parts.iter().skip(1).map(|s| s.as_str()).collect();
+        let (assignments, command_line) = split_assignments(stage);
 
         match unsafe { fork() } {
             Ok(ForkResult::Child) => {
+                shell.traps.reset_for_subshell();
                 // If not first, read from previous pipe
                 if i > 0 {
+                    shell.input_buffered = false;
                     let (prev_read, _) = &pipes[i - 1];
                     unsafe { libc::dup2(prev_read.as_raw_fd(), libc::STDIN_FILENO) };
                 }
@@ -634,6 +3050,72 @@ fn handle_pipeline(cmd_line: &str) {
                     unsafe { libc::dup2(next_write.as_raw_fd(), libc::STDOUT_FILENO) };
                 }
 
+                // Close all pipe fds
+                for (r_fd, w_fd) in &pipes {
+                    let _ = close(r_fd.as_raw_fd());
+                    let _ = close(w_fd.as_raw_fd());
+                }
+
+                // Each stage runs in its own process, so it expands its words
+                // there: `$BASHPID` and the like see the child. As for a
+                // lone command, they are expanded before the prefix
+                // assignments are made.
+                let parts = (!is_conditional(command_line)).then(|| {
+                    tokenize(command_line, shell).unwrap_or_else(|e| {
+                        eprintln!("{}", e);
+                        process::exit(1);
+                    })
+                });
+                let mut traced = Vec::new();
+                for assignment in &assignments {
+                    match assign(shell, assignment, true) {
+                        Ok(word) => traced.push(word),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            process::exit(1);
+                        }
+                    }
+                    shell.vars.set_exported(assignment.name, true);
+                }
+                let Some(parts) = parts else {
+                    process::exit(run_conditional(shell, command_line, &traced));
+                };
+                traced.extend(traced_words(&parts));
+                xtrace::trace(shell, &traced);
+
+                let parts = match lookup_prefix(&parts) {
+                    Ok((start, default_path)) => {
+                        if default_path {
+                            let _ = shell.vars.set("PATH", DEFAULT_PATH);
+                        }
+                        parts[start..].to_vec()
+                    }
+                    Err(status) => process::exit(status),
+                };
+                let args: Vec<&str> = parts.iter().skip(1).map(|s| s.as_str()).collect();
+                let Some(cmd_name) = parts.first().map(String::as_str) else {
+                    process::exit(0);
+                };
+
+                // An external command is looked up with the shell's PATH
+                // before stderr goes, so a missing one is still reported.
+                let program = if is_builtin(cmd_name) {
+                    None
+                } else {
+                    let path_var = shell.vars.get("PATH").map(str::to_string);
+                    let Some(program) = shell.hash.find(cmd_name, path_var.as_deref()) else {
+                        eprintln!("{}: command not found", cmd_name);
+                        process::exit(127);
+                    };
+                    if let Err(status) = check_runnable(cmd_name, &program) {
+                        process::exit(status);
+                    }
+                    Some(program)
+                };
+
+                // A copy of stderr, closed on exec, to report a program
+                // that fails to run on.
+                let stderr_copy = unsafe { libc::fcntl(libc::STDERR_FILENO, libc::F_DUPFD_CLOEXEC, 3) };
                 // **Suppress Broken pipe** on intermediate stages
                 if i < num_cmds - 1 {
                     if let Ok(devnull) = OpenOptions::new().read(true).open("/dev/null") {
@@ -643,35 +3125,52 @@ fn handle_pipeline(cmd_line: &str) {
                     }
                 }
 
-                // Close all pipe fds
-                for (r_fd, w_fd) in &pipes {
-                    let _ = close(r_fd.as_raw_fd());
-                    let _ = close(w_fd.as_raw_fd());
-                }
-
                 // Builtin?
-                if is_builtin(cmd_name) {
-                    run_builtin(cmd_name, &args, None, None);
-                    process::exit(0);
-                }
+                let Some(program) = program else {
+                    let status = run_builtin(shell, cmd_name, &args, None, None);
+                    let _ = std::io::stdout().flush();
+                    process::exit(status);
+                };
 
-                // External exec
+                // External exec, with the shell's exported variables as the
+                // environment.
                 let cstrs: Vec<CString> = parts
                     .iter()
                     .map(|s| CString::new(s.as_str()).unwrap())
                     .collect();
-                execvp(&cstrs[0], &cstrs).unwrap_or_else(|e| {
-                    eprintln!("execvp failed: {}", e);
-                    process::exit(1);
-                });
-                unreachable!();
-            }
-            Ok(ForkResult::Parent { .. }) => {
-                // parent continues to next stage
+                let env: Vec<CString> = shell
+                    .vars
+                    .exported()
+                    .filter_map(|(name, value)| CString::new(format!("{}={}", name, value)).ok())
+                    .collect();
+                let program_c = CString::new(program.as_os_str().as_bytes()).unwrap();
+                let mut e = execve(&program_c, &cstrs, &env).unwrap_err();
+                // A text file the kernel can't execute is run as a script by
+                // a fresh shell, with the arguments after it.
+                if e == nix::errno::Errno::ENOEXEC && !binary_file(&program) {
+                    let shell_exe = match env::current_exe() {
+                        Ok(exe) => CString::new(exe.into_os_string().into_vec()).unwrap(),
+                        Err(e) => process::exit(exec_failure(cmd_name, &e)),
+                    };
+                    let argv: Vec<CString> =
+                        [shell_exe.clone(), program_c].into_iter().chain(cstrs.into_iter().skip(1)).collect();
+                    e = execve(&shell_exe, &argv, &env).unwrap_err();
+                }
+                if stderr_copy >= 0 {
+                    unsafe {
+                        libc::dup2(stderr_copy, libc::STDERR_FILENO);
+                    }
+                }
+                if e == nix::errno::Errno::ENOEXEC {
+                    eprintln!("{}: cannot execute binary file: Exec format error", cmd_name);
+                    process::exit(126);
+                }
+                process::exit(exec_failure(cmd_name, &e.into()));
             }
+            Ok(ForkResult::Parent { child }) => children.push(child),
             Err(e) => {
                 eprintln!("fork failed: {}", e);
-                return;
+                return 1;
             }
         }
     }
@@ -682,9 +3181,12 @@ fn handle_pipeline(cmd_line: &str) {
         let _ = close(w_fd);
     }
 
-    // Wait for all children
-    for _ in 0..num_cmds {
-        let _ = waitpid(None, None);
+    // Wait for every stage, in order, collecting its status.
+    let statuses: Vec<i32> = children.into_iter().map(wait_for).collect();
+    if shell.options.pipefail {
+        statuses.iter().rev().find(|&&status| status != 0).copied().unwrap_or(0)
+    } else {
+        statuses.last().copied().unwrap_or(0)
     }
 }
 
@@ -0,0 +1,95 @@
+//! The shell options managed by `set`, each with its `-o` name and, for
+//! most, a single-letter form.
+
+/// Every option, in the order `set -o` lists them.
+pub const OPTIONS: [(&str, Option<char>); 10] = [
+    ("allexport", Some('a')),
+    ("errexit", Some('e')),
+    ("histexpand", Some('H')),
+    ("noexec", Some('n')),
+    ("noglob", Some('f')),
+    ("nounset", Some('u')),
+    ("pipefail", None),
+    ("verbose", Some('v')),
+    ("xpg_echo", None),
+    ("xtrace", Some('x')),
+];
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    /// Export every variable that is assigned.
+    pub allexport: bool,
+    /// Exit when a command fails, outside the exempt contexts.
+    pub errexit: bool,
+    /// Expand `!` history references in lines typed at the prompt; on by
+    /// default in interactive shells.
+    pub histexpand: bool,
+    /// Parse commands without running them; ignored when interactive.
+    pub noexec: bool,
+    /// Disable pathname expansion.
+    pub noglob: bool,
+    /// Treat expanding an unset variable as an error.
+    pub nounset: bool,
+    /// A pipeline fails if any stage fails, not just the last.
+    pub pipefail: bool,
+    /// Print each line before running it.
+    pub verbose: bool,
+    /// `echo` takes no options and always interprets backslash escapes.
+    pub xpg_echo: bool,
+    /// Print each command after expansion, prefixed with `$PS4`.
+    pub xtrace: bool,
+}
+
+impl Options {
+    fn slot(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "allexport" => Some(&mut self.allexport),
+            "errexit" => Some(&mut self.errexit),
+            "histexpand" => Some(&mut self.histexpand),
+            "noexec" => Some(&mut self.noexec),
+            "noglob" => Some(&mut self.noglob),
+            "nounset" => Some(&mut self.nounset),
+            "pipefail" => Some(&mut self.pipefail),
+            "verbose" => Some(&mut self.verbose),
+            "xpg_echo" => Some(&mut self.xpg_echo),
+            "xtrace" => Some(&mut self.xtrace),
+            _ => None,
+        }
+    }
+
+    /// Whether the option called `name` is on, or `None` if there is no
+    /// such option.
+    pub fn get(&self, name: &str) -> Option<bool> {
+        let mut options = *self;
+        options.slot(name).map(|value| *value)
+    }
+
+    /// Turns the option called `name` on or off. Returns `false` if there is
+    /// no such option.
+    pub fn set(&mut self, name: &str, on: bool) -> bool {
+        match self.slot(name) {
+            Some(value) => {
+                *value = on;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The long name of the option with the single-letter form `letter`.
+    pub fn name_of(letter: char) -> Option<&'static str> {
+        OPTIONS
+            .iter()
+            .find(|(_, short)| *short == Some(letter))
+            .map(|(name, _)| *name)
+    }
+
+    /// The letters of the options that are on, as `$-` shows them.
+    pub fn letters(&self) -> String {
+        OPTIONS
+            .iter()
+            .filter(|(name, _)| self.get(name) == Some(true))
+            .filter_map(|(_, letter)| *letter)
+            .collect()
+    }
+}
@@ -0,0 +1,269 @@
+//! Splits a command line into the lists, AND-OR lists and pipelines that
+//! decide how its commands run: `;`, `&`, `&&`, `||`, `|`, a leading `!`
+//! and `#` comments. The commands themselves are left as text for the caller to
+//! split into words, and so is everything between `[[` and `]]`.
+
+/// Commands joined by `|`, optionally negated with a leading `!`.
+pub struct Pipeline<'a> {
+    pub negated: bool,
+    pub stages: Vec<&'a str>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+/// Pipelines joined by `&&` and `||`, which run left to right. One ended by
+/// `&` runs in the background.
+pub struct AndOr<'a> {
+    pub first: Pipeline<'a>,
+    pub rest: Vec<(Connector, Pipeline<'a>)>,
+    pub background: bool,
+}
+
+/// A syntax error and the 1-based column, in characters, where it was found.
+#[derive(Debug)]
+pub struct SyntaxError {
+    pub column: usize,
+    pub message: String,
+}
+
+impl std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+fn error(line: &str, offset: usize, message: String) -> SyntaxError {
+    SyntaxError { column: line[..offset].chars().count() + 1, message }
+}
+
+enum Token<'a> {
+    Text(&'a str),
+    Pipe,
+    And,
+    Or,
+    Semicolon,
+    Background,
+}
+
+impl Token<'_> {
+    fn operator(&self) -> &'static str {
+        match self {
+            Token::Text(_) => "",
+            Token::Pipe => "|",
+            Token::And => "&&",
+            Token::Or => "||",
+            Token::Semicolon => ";",
+            Token::Background => "&",
+        }
+    }
+}
+
+/// Parses `line` into the AND-OR lists separated by `;` or `&`.
+pub fn parse_list(line: &str) -> Result<Vec<AndOr<'_>>, SyntaxError> {
+    let mut list = Vec::new();
+    let mut and_or: Option<AndOr> = None;
+    let mut connector = None;
+    let mut stages = Vec::new();
+
+    // The tokens alternate between command text and operators, starting
+    // and ending with text; each comes with its offset in `line`.
+    let mut tokens = split_operators(line)?.into_iter();
+    while let Some((offset, Token::Text(text))) = tokens.next() {
+        let operator = tokens.next();
+        if text.trim().is_empty() {
+            return match operator {
+                Some((offset, operator)) => Err(error(
+                    line,
+                    offset,
+                    format!("syntax error near unexpected token `{}'", operator.operator()),
+                )),
+                None if !stages.is_empty() || connector.is_some() => {
+                    Err(error(line, line.len(), "syntax error: unexpected end of file".to_string()))
+                }
+                None => Ok(list),
+            };
+        }
+
+        stages.push((offset, text));
+        if matches!(operator, Some((_, Token::Pipe))) {
+            continue;
+        }
+        let pipeline = pipeline(line, std::mem::take(&mut stages))?;
+        match (and_or.as_mut(), connector.take()) {
+            (Some(and_or), Some(connector)) => and_or.rest.push((connector, pipeline)),
+            _ => and_or = Some(AndOr { first: pipeline, rest: Vec::new(), background: false }),
+        }
+        match operator {
+            Some((_, Token::And)) => connector = Some(Connector::And),
+            Some((_, Token::Or)) => connector = Some(Connector::Or),
+            Some((_, Token::Background)) => {
+                list.extend(and_or.take().map(|and_or| AndOr { background: true, ..and_or }));
+            }
+            _ => list.extend(and_or.take()),
+        }
+    }
+    Ok(list)
+}
+
+/// Builds a pipeline from the text of its stages and their offsets in
+/// `line`.
+fn pipeline<'a>(line: &str, stages: Vec<(usize, &'a str)>) -> Result<Pipeline<'a>, SyntaxError> {
+    // The `|` after the first stage sits just before the second.
+    let pipe = stages.get(1).map(|&(offset, _)| offset - 1);
+    let mut stages: Vec<&str> = stages.into_iter().map(|(_, text)| text.trim()).collect();
+    let mut negated = false;
+    if let Some(rest) = stages[0].strip_prefix('!').filter(|rest| rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        negated = true;
+        stages[0] = rest.trim_start();
+        if stages[0].is_empty() {
+            let (offset, next) = match pipe {
+                Some(offset) => (offset, "|"),
+                None => (line.len(), "newline"),
+            };
+            return Err(error(line, offset, format!("syntax error near unexpected token `{}'", next)));
+        }
+    }
+    Ok(Pipeline { negated, stages })
+}
+
+/// Cuts `line` at the unquoted control operators, dropping a trailing
+/// comment. Text inside quotes, `${...}` and the parentheses of a
+/// `NAME=(...)` assignment or a `[[ ... ]]` conditional is never split; any
+/// of these left open at the end of the line is an error.
+fn split_operators(line: &str) -> Result<Vec<(usize, Token<'_>)>, SyntaxError> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    let mut in_single_quotes = false;
+    let mut in_double_quotes = false;
+    let mut escaped = false;
+    // Where each open quote, `${` or `(` started, innermost last.
+    let mut open: Vec<(usize, char)> = Vec::new();
+    let mut braces = 0;
+    let mut parens = 0;
+    let mut in_brackets = false;
+    // Whether the next word starts a command, the only place `[[` opens a
+    // conditional: at the start of the line, after an operator or after `!`.
+    let mut command_position = true;
+    let mut previous = None;
+    let mut chars = line.char_indices().peekable();
+
+    while let Some((i, ch)) = chars.next() {
+        let word_start = previous.map_or(true, |c: char| c.is_whitespace() || ";|&".contains(c));
+        previous = Some(ch);
+        let at_command = command_position;
+        if word_start && !ch.is_whitespace() && !";|&".contains(ch) {
+            command_position = ch == '!' && is_keyword(&line[i..], "!", "");
+        }
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        if in_single_quotes {
+            if ch == '\'' {
+                in_single_quotes = false;
+                open.pop();
+            }
+            continue;
+        }
+        match ch {
+            '\\' => escaped = true,
+            '"' => {
+                in_double_quotes = !in_double_quotes;
+                if in_double_quotes {
+                    open.push((i, '"'));
+                } else {
+                    open.pop();
+                }
+            }
+            '\'' if !in_double_quotes => {
+                in_single_quotes = true;
+                open.push((i, '\''));
+            }
+            '$' if chars.peek().is_some_and(|&(_, next)| next == '{') => {
+                chars.next();
+                previous = Some('{');
+                braces += 1;
+                open.push((i, '{'));
+            }
+            '}' if braces > 0 => {
+                braces -= 1;
+                open.pop();
+            }
+            _ if in_double_quotes || braces > 0 => {}
+            '[' if word_start && at_command && !in_brackets && parens == 0 && is_keyword(&line[i..], "[[", "") => {
+                chars.next();
+                in_brackets = true;
+                open.push((i, '['));
+            }
+            ']' if word_start && in_brackets && is_keyword(&line[i..], "]]", ";|&") => {
+                chars.next();
+                previous = Some(']');
+                in_brackets = false;
+                open.pop();
+            }
+            _ if in_brackets => {}
+            '(' if parens > 0 || line[..i].ends_with('=') => {
+                parens += 1;
+                open.push((i, '('));
+            }
+            ')' if parens > 0 => {
+                parens -= 1;
+                open.pop();
+            }
+            _ if parens > 0 => {}
+            '#' if word_start => {
+                tokens.push((start, Token::Text(&line[start..i])));
+                return Ok(tokens);
+            }
+            ';' | '|' | '&' => {
+                let doubled = chars.peek().is_some_and(|&(_, next)| next == ch && ch != ';');
+                let token = match (ch, doubled) {
+                    (';', _) => Token::Semicolon,
+                    ('|', false) => Token::Pipe,
+                    ('|', true) => Token::Or,
+                    ('&', true) => Token::And,
+                    // A `&` in a redirection, as in `2>&1` or `&>file`, is
+                    // part of the word.
+                    ('&', false)
+                        if line[..i].ends_with(['>', '<'])
+                            || chars.peek().is_some_and(|&(_, next)| next == '>') =>
+                    {
+                        continue
+                    }
+                    _ => Token::Background,
+                };
+                tokens.push((start, Token::Text(&line[start..i])));
+                tokens.push((i, token));
+                command_position = true;
+                if doubled {
+                    chars.next();
+                }
+                start = i + if doubled { 2 } else { 1 };
+            }
+            _ => {}
+        }
+    }
+    if let Some(&(offset, opener)) = open.last() {
+        let closer = match opener {
+            '{' => "}",
+            '(' => ")",
+            '[' => "]]",
+            '"' => "\"",
+            _ => "'",
+        };
+        return Err(error(line, offset, format!("unexpected end of line while looking for matching `{}'", closer)));
+    }
+    tokens.push((start, Token::Text(&line[start..])));
+    Ok(tokens)
+}
+
+/// Whether `text` starts with the reserved word `word`, followed by a blank,
+/// the end of the line or one of `terminators`.
+fn is_keyword(text: &str, word: &str, terminators: &str) -> bool {
+    text.strip_prefix(word)
+        .is_some_and(|rest| rest.chars().next().map_or(true, |c| c.is_whitespace() || terminators.contains(c)))
+}
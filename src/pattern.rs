@@ -0,0 +1,186 @@
+//! Shell glob patterns: `*`, `?`, bracket expressions such as `[a-z]`,
+//! `[!0-9]` and `[[:alpha:]]`, and backslash escapes; and pathname
+//! expansion, which matches them against the file system.
+
+use std::fs;
+
+/// Whether `pattern` matches the whole of `text`.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    match_chars(&pattern, &text)
+}
+
+/// Escapes the characters that are special in a pattern, so the result only
+/// matches `text` itself.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if "*?[\\".contains(ch) {
+            escaped.push('\\');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+/// Pathname expansion: the paths matching `pattern`, sorted, or an empty
+/// list if there are none. Each `/`-separated component is matched against
+/// the names in one directory; names starting with `.` only match a
+/// component that starts with `.` too.
+pub fn expand_path(pattern: &str) -> Vec<String> {
+    let components: Vec<&str> = pattern.split('/').collect();
+    let mut paths = vec![String::new()];
+
+    for (i, component) in components.iter().enumerate() {
+        let separator = if i + 1 < components.len() { "/" } else { "" };
+        if !component.contains(['*', '?', '[']) {
+            let literal = unescape(component);
+            for path in &mut paths {
+                path.push_str(&literal);
+                path.push_str(separator);
+            }
+            continue;
+        }
+
+        let mut matched = Vec::new();
+        for path in &paths {
+            let dir = if path.is_empty() { "." } else { path.as_str() };
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let Ok(name) = entry.file_name().into_string() else {
+                    continue;
+                };
+                if (!name.starts_with('.') || component.starts_with('.')) && matches(component, &name) {
+                    matched.push(format!("{}{}{}", path, name, separator));
+                }
+            }
+        }
+        paths = matched;
+    }
+
+    // Literal components were not checked against the directory listing.
+    paths.retain(|path| fs::symlink_metadata(path).is_ok());
+    paths.sort();
+    paths
+}
+
+fn unescape(component: &str) -> String {
+    let mut literal = String::with_capacity(component.len());
+    let mut chars = component.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => literal.extend(chars.next()),
+            _ => literal.push(ch),
+        }
+    }
+    literal
+}
+
+fn match_chars(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the most recent `*`: the pattern position after
+    // it, and the text position it has swallowed up to.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        let next = match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, t));
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => match match_bracket(pattern, p, text[t]) {
+                Some((matched, end)) => matched.then_some(end),
+                None => (text[t] == '[').then_some(p + 1),
+            },
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(p + 2),
+            Some(&c) => (c == text[t]).then_some(p + 1),
+            None => None,
+        };
+        match (next, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            }
+            (None, Some((star_p, star_t))) => {
+                p = star_p;
+                t = star_t + 1;
+                backtrack = Some((star_p, star_t + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Matches `ch` against the bracket expression starting at `pattern[start]`.
+/// Returns whether it matched and the position after the closing `]`, or
+/// `None` if the `[` is unterminated and so stands for itself.
+fn match_bracket(pattern: &[char], start: usize, ch: char) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negated = matches!(pattern.get(i), Some('!' | '^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+
+    loop {
+        let c = *pattern.get(i)?;
+        if c == ']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+
+        if c == '[' && pattern.get(i + 1) == Some(&':') {
+            let rest = &pattern[i + 2..];
+            if let Some(len) = rest.windows(2).position(|w| w == [':', ']']) {
+                let class: String = rest[..len].iter().collect();
+                matched |= class_matches(&class, ch);
+                i += len + 4;
+                continue;
+            }
+        }
+
+        let low = if c == '\\' {
+            i += 1;
+            *pattern.get(i)?
+        } else {
+            c
+        };
+        i += 1;
+        if pattern.get(i) == Some(&'-') && pattern.get(i + 1).is_some_and(|&c| c != ']') {
+            let mut high = pattern[i + 1];
+            i += 2;
+            if high == '\\' {
+                high = *pattern.get(i)?;
+                i += 1;
+            }
+            matched |= (low..=high).contains(&ch);
+        } else {
+            matched |= low == ch;
+        }
+    }
+}
+
+fn class_matches(class: &str, ch: char) -> bool {
+    match class {
+        "alnum" => ch.is_alphanumeric(),
+        "alpha" => ch.is_alphabetic(),
+        "blank" => ch == ' ' || ch == '\t',
+        "cntrl" => ch.is_control(),
+        "digit" => ch.is_ascii_digit(),
+        "graph" => ch.is_ascii_graphic(),
+        "lower" => ch.is_lowercase(),
+        "print" => ch.is_ascii_graphic() || ch == ' ',
+        "punct" => ch.is_ascii_punctuation(),
+        "space" => ch.is_whitespace(),
+        "upper" => ch.is_uppercase(),
+        "xdigit" => ch.is_ascii_hexdigit(),
+        _ => false,
+    }
+}
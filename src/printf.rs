@@ -0,0 +1,449 @@
+//! The formatting behind the `printf` builtin: C-style conversions, with
+//! the shell's additions `%b`, `%q` and `%(fmt)T`.
+
+use std::ffi::CString;
+
+/// What a call to `printf` produced: its output, and a message for each
+/// argument or conversion that was wrong.
+pub struct Formatted {
+    pub output: String,
+    pub errors: Vec<String>,
+}
+
+/// Formats `args` according to `format`. The format is used again for as
+/// long as arguments remain; conversions without an argument see an empty
+/// string or zero.
+pub fn format(format: &str, args: &[&str]) -> Formatted {
+    let mut printer = Printer { args, next: 0, output: String::new(), errors: Vec::new(), stopped: false };
+    loop {
+        let start = printer.next;
+        if !printer.run(format) {
+            break;
+        }
+        if printer.next == start || printer.next >= args.len() {
+            break;
+        }
+    }
+    Formatted { output: printer.output, errors: printer.errors }
+}
+
+struct Printer<'a> {
+    args: &'a [&'a str],
+    next: usize,
+    output: String,
+    errors: Vec<String>,
+    /// Set by `\c` in a `%b` argument, which ends all output.
+    stopped: bool,
+}
+
+/// One `%` conversion, with its flags and the width and precision already
+/// resolved from `*` arguments.
+struct Spec {
+    flags: String,
+    width: Option<usize>,
+    precision: Option<usize>,
+    conversion: char,
+}
+
+impl<'a> Printer<'a> {
+    /// Makes one pass over the format. Returns `false` if output must stop.
+    fn run(&mut self, format: &str) -> bool {
+        let mut chars = format.chars().peekable();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' => {
+                    if let Escape::Char(ch) = escape(&mut chars, false) {
+                        self.output.push_str(&ch);
+                    }
+                }
+                '%' if chars.peek() == Some(&'%') => {
+                    chars.next();
+                    self.output.push('%');
+                }
+                '%' => {
+                    let mut flags = String::new();
+                    while let Some(&flag) = chars.peek().filter(|c| "-+ #0".contains(**c)) {
+                        flags.push(flag);
+                        chars.next();
+                    }
+                    let Ok(width) = self.number(&mut chars) else {
+                        return false;
+                    };
+                    let precision = match chars.peek() {
+                        Some('.') => {
+                            chars.next();
+                            let Ok(precision) = self.number(&mut chars) else {
+                                return false;
+                            };
+                            Some(precision.unwrap_or(0))
+                        }
+                        _ => None,
+                    };
+                    // `-` given through `*` also left-aligns.
+                    let (width, flags) = match width {
+                        Some(width) if width < 0 => (Some(width.unsigned_abs() as usize), flags + "-"),
+                        width => (width.map(|width| width as usize), flags),
+                    };
+                    let precision = precision.and_then(|precision| usize::try_from(precision).ok());
+
+                    let time_format = match chars.peek() {
+                        Some('(') => {
+                            chars.next();
+                            Some(chars.by_ref().take_while(|&c| c != ')').collect::<String>())
+                        }
+                        _ => None,
+                    };
+                    while chars.next_if(|c| "hlLjzt".contains(*c)).is_some() {}
+                    let Some(conversion) = chars.next() else {
+                        self.errors.push("missing format character".to_string());
+                        return false;
+                    };
+                    let spec = Spec { flags, width, precision, conversion };
+                    match time_format {
+                        Some(time_format) if conversion == 'T' => self.time(&spec, &time_format),
+                        Some(_) => {
+                            self.errors.push(format!("`{}': invalid time format specification", conversion));
+                            return false;
+                        }
+                        None => {
+                            if !self.convert(&spec) {
+                                return false;
+                            }
+                        }
+                    }
+                    if self.stopped {
+                        return false;
+                    }
+                }
+                _ => self.output.push(ch),
+            }
+        }
+        true
+    }
+
+    fn arg(&mut self) -> Option<&'a str> {
+        let arg = self.args.get(self.next).copied();
+        if arg.is_some() {
+            self.next += 1;
+        }
+        arg
+    }
+
+    /// A width or precision: digits, or `*` to take it from the arguments.
+    /// One too large for a C `int` is reported, and output stops there.
+    fn number(&mut self, chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Option<i64>, ()> {
+        let (text, value) = if chars.next_if_eq(&'*').is_some() {
+            let arg = self.arg().unwrap_or("");
+            (arg.to_string(), self.integer(arg))
+        } else {
+            let mut digits = String::new();
+            while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                digits.push(digit);
+            }
+            if digits.is_empty() {
+                return Ok(None);
+            }
+            let value = digits.parse().unwrap_or(i64::MAX);
+            (digits, value)
+        };
+        if value.unsigned_abs() > i32::MAX as u64 {
+            self.errors.push(format!("{}: Numerical result out of range", text));
+            return Err(());
+        }
+        Ok(Some(value))
+    }
+
+    fn convert(&mut self, spec: &Spec) -> bool {
+        match spec.conversion {
+            'd' | 'i' | 'u' | 'o' | 'x' | 'X' => {
+                let arg = self.arg().unwrap_or("");
+                let value = self.integer(arg);
+                let conversion = if spec.conversion == 'i' { 'd' } else { spec.conversion };
+                let formatted = c_format(spec, &format!("ll{}", conversion), |format, buf, len| unsafe {
+                    libc::snprintf(buf, len, format, value as libc::c_longlong)
+                });
+                self.output.push_str(&formatted);
+            }
+            'e' | 'E' | 'f' | 'F' | 'g' | 'G' | 'a' | 'A' => {
+                let arg = self.arg().unwrap_or("");
+                let value = self.float(arg);
+                let formatted = c_format(spec, &spec.conversion.to_string(), |format, buf, len| unsafe {
+                    libc::snprintf(buf, len, format, value as libc::c_double)
+                });
+                self.output.push_str(&formatted);
+            }
+            's' => {
+                let arg = self.arg().unwrap_or("").to_string();
+                self.pad(spec, &arg);
+            }
+            'c' => {
+                let arg = self.arg().unwrap_or("");
+                let ch: String = arg.chars().take(1).collect();
+                self.pad(spec, &ch);
+            }
+            'b' => {
+                let arg = self.arg().unwrap_or("");
+                let mut expanded = String::new();
+                let mut chars = arg.chars().peekable();
+                while let Some(ch) = chars.next() {
+                    if ch != '\\' {
+                        expanded.push(ch);
+                        continue;
+                    }
+                    match escape(&mut chars, true) {
+                        Escape::Char(ch) => expanded.push_str(&ch),
+                        Escape::Stop => {
+                            self.stopped = true;
+                            break;
+                        }
+                    }
+                }
+                self.pad(spec, &expanded);
+            }
+            'q' => {
+                let arg = self.arg().unwrap_or("");
+                self.pad(spec, &quote(arg));
+            }
+            other => {
+                self.errors.push(format!("`{}': invalid format character", other));
+                return false;
+            }
+        }
+        true
+    }
+
+    /// `%(fmt)T`: the argument is seconds since the epoch, with -1 or no
+    /// argument meaning now.
+    fn time(&mut self, spec: &Spec, time_format: &str) {
+        let time = match self.arg() {
+            Some(arg) => self.integer(arg),
+            None => -1,
+        };
+        let time = if time < 0 {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |now| now.as_secs() as i64)
+        } else {
+            time
+        };
+        let time_format = if time_format.is_empty() { "%X" } else { time_format };
+        let formatted = strftime(time_format, time);
+        self.pad(spec, &formatted);
+    }
+
+    /// Pads `text` to the width, after cutting it to the precision.
+    fn pad(&mut self, spec: &Spec, text: &str) {
+        let text: String = match spec.precision {
+            Some(precision) => text.chars().take(precision).collect(),
+            None => text.to_string(),
+        };
+        let padding = " ".repeat(spec.width.unwrap_or(0).saturating_sub(text.chars().count()));
+        if spec.flags.contains('-') {
+            self.output.push_str(&text);
+            self.output.push_str(&padding);
+        } else {
+            self.output.push_str(&padding);
+            self.output.push_str(&text);
+        }
+    }
+
+    /// An integer argument: decimal, `0x` hex, leading-zero octal, or a
+    /// quote followed by a character for its code point. Anything left over
+    /// is reported, and the number read so far is used; so is one too large
+    /// for 64 bits, which is clamped.
+    fn integer(&mut self, arg: &str) -> i64 {
+        let text = arg.trim_start();
+        if let Some(rest) = text.strip_prefix(['\'', '"']) {
+            return rest.chars().next().map_or(0, |ch| ch as i64);
+        }
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, text.strip_prefix('+').unwrap_or(text)),
+        };
+        let (radix, digits) = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+            (16, hex)
+        } else if digits.len() > 1 && digits.starts_with('0') {
+            (8, &digits[1..])
+        } else {
+            (10, digits)
+        };
+        let end = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+        let magnitude = if end == 0 { Some(0) } else { u64::from_str_radix(&digits[..end], radix).ok() };
+        let value = match magnitude {
+            Some(magnitude) if negative => 0i64.checked_sub_unsigned(magnitude),
+            Some(magnitude) => i64::try_from(magnitude).ok(),
+            None => None,
+        };
+        if end < digits.len() || (end == 0 && !text.is_empty()) {
+            self.errors.push(format!("{}: invalid number", arg));
+        }
+        value.unwrap_or_else(|| {
+            self.errors.push(format!("{}: Result too large", arg));
+            if negative {
+                i64::MIN
+            } else {
+                i64::MAX
+            }
+        })
+    }
+
+    fn float(&mut self, arg: &str) -> f64 {
+        let text = arg.trim();
+        if text.is_empty() {
+            return 0.0;
+        }
+        if text.starts_with(['\'', '"']) || text.contains(['x', 'X']) {
+            return self.integer(arg) as f64;
+        }
+        text.parse().unwrap_or_else(|_| {
+            self.errors.push(format!("{}: invalid number", arg));
+            0.0
+        })
+    }
+}
+
+/// Formats one value with the C library, for conversions whose exact
+/// output (rounding, exponents, `#` forms) should match C's.
+fn c_format(
+    spec: &Spec,
+    conversion: &str,
+    print: impl Fn(*const libc::c_char, *mut libc::c_char, usize) -> libc::c_int,
+) -> String {
+    let mut format = format!("%{}", spec.flags);
+    if let Some(width) = spec.width {
+        format.push_str(&width.to_string());
+    }
+    if let Some(precision) = spec.precision {
+        format.push_str(&format!(".{}", precision));
+    }
+    format.push_str(conversion);
+    let Ok(format) = CString::new(format) else {
+        return String::new();
+    };
+    let len = print(format.as_ptr(), std::ptr::null_mut(), 0);
+    let Ok(len) = usize::try_from(len) else {
+        return String::new();
+    };
+    let mut buf = vec![0u8; len + 1];
+    print(format.as_ptr(), buf.as_mut_ptr().cast(), buf.len());
+    buf.truncate(len);
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+fn strftime(format: &str, time: i64) -> String {
+    let Ok(format) = CString::new(format) else {
+        return String::new();
+    };
+    let mut buf = vec![0u8; 256];
+    let len = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        let time = time as libc::time_t;
+        if libc::localtime_r(&time, &mut tm).is_null() {
+            return String::new();
+        }
+        libc::strftime(buf.as_mut_ptr().cast(), buf.len(), format.as_ptr(), &tm)
+    };
+    buf.truncate(len);
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+pub enum Escape {
+    /// The text the escape stands for.
+    Char(String),
+    /// `\c`, in a `%b` argument: produce no further output.
+    Stop,
+}
+
+/// Reads the backslash escape after a `\`. In a `%b` argument (`in_arg`)
+/// octal escapes are written `\0nnn` and `\c` stops the output; in the
+/// format they are `\nnn`. An unknown escape stands for itself, backslash
+/// included.
+pub fn escape(chars: &mut std::iter::Peekable<std::str::Chars>, in_arg: bool) -> Escape {
+    let Some(ch) = chars.next() else {
+        return Escape::Char("\\".to_string());
+    };
+    let code = |chars: &mut std::iter::Peekable<std::str::Chars>, radix: u32, max: usize| {
+        let mut digits = String::new();
+        while digits.len() < max {
+            match chars.next_if(|c| c.is_digit(radix)) {
+                Some(digit) => digits.push(digit),
+                None => break,
+            }
+        }
+        (!digits.is_empty()).then(|| u32::from_str_radix(&digits, radix).unwrap_or(0))
+    };
+    let text = match ch {
+        'a' => "\x07".to_string(),
+        'b' => "\x08".to_string(),
+        'e' | 'E' => "\x1b".to_string(),
+        'f' => "\x0c".to_string(),
+        'n' => "\n".to_string(),
+        'r' => "\r".to_string(),
+        't' => "\t".to_string(),
+        'v' => "\x0b".to_string(),
+        '\\' => "\\".to_string(),
+        '"' | '\'' | '?' if !in_arg => ch.to_string(),
+        'c' if in_arg => return Escape::Stop,
+        '0' if in_arg => byte(code(chars, 8, 3).unwrap_or(0)),
+        '0'..='7' if !in_arg => {
+            let mut value = ch.to_digit(8).unwrap_or(0);
+            for _ in 0..2 {
+                match chars.next_if(|c| c.is_digit(8)) {
+                    Some(digit) => value = value * 8 + digit.to_digit(8).unwrap_or(0),
+                    None => break,
+                }
+            }
+            byte(value)
+        }
+        'x' => match code(chars, 16, 2) {
+            Some(value) => byte(value),
+            None => "\\x".to_string(),
+        },
+        'u' | 'U' => match code(chars, 16, if ch == 'u' { 4 } else { 8 }) {
+            Some(value) => char::from_u32(value).unwrap_or(char::REPLACEMENT_CHARACTER).to_string(),
+            None => format!("\\{}", ch),
+        },
+        _ => format!("\\{}", ch),
+    };
+    Escape::Char(text)
+}
+
+/// The character for a byte value written as an octal or hex escape.
+fn byte(value: u32) -> String {
+    char::from_u32(value & 0xff).unwrap_or(char::REPLACEMENT_CHARACTER).to_string()
+}
+
+/// `%q`: quotes `text` so the shell reads it back as the same word, with
+/// backslashes, or `$'...'` when it holds control characters.
+pub fn quote(text: &str) -> String {
+    if text.is_empty() {
+        return "''".to_string();
+    }
+    if text.chars().any(|c| c.is_control()) {
+        let mut quoted = String::from("$'");
+        for ch in text.chars() {
+            match ch {
+                '\n' => quoted.push_str("\\n"),
+                '\t' => quoted.push_str("\\t"),
+                '\r' => quoted.push_str("\\r"),
+                '\x1b' => quoted.push_str("\\E"),
+                '\\' | '\'' => {
+                    quoted.push('\\');
+                    quoted.push(ch);
+                }
+                _ if ch.is_control() => quoted.push_str(&format!("\\{:03o}", ch as u32)),
+                _ => quoted.push(ch),
+            }
+        }
+        quoted.push('\'');
+        return quoted;
+    }
+    let mut quoted = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if !(ch.is_alphanumeric() || "-_./,:=+@%^".contains(ch)) {
+            quoted.push('\\');
+        }
+        quoted.push(ch);
+    }
+    quoted
+}
@@ -0,0 +1,317 @@
+//! The input side of the `read` builtin: taking one line, or up to a
+//! delimiter or a number of characters, from standard input, and splitting
+//! it into fields at the characters in `$IFS`.
+
+use std::io::{self, BufRead};
+use std::time::{Duration, Instant};
+
+/// How `read` takes its input, from its options.
+pub struct ReadOptions {
+    /// `-r`: backslashes are ordinary characters.
+    pub raw: bool,
+    /// `-d`: the byte that ends the input; a newline by default.
+    pub delimiter: u8,
+    /// `-n`: stop after this many characters.
+    pub count: Option<usize>,
+    /// `-t`: give up if the input is not complete by then.
+    pub timeout: Option<Duration>,
+    /// `-s`: don't echo what is typed on a terminal.
+    pub silent: bool,
+    /// Read through the standard library's stdin buffer, which holds the
+    /// rest of the shell's own input when commands come from stdin.
+    pub buffered: bool,
+}
+
+/// Why reading stopped.
+#[derive(PartialEq)]
+pub enum End {
+    /// The delimiter was read or the character count reached.
+    Complete,
+    /// End of file before the delimiter.
+    Eof,
+    TimedOut,
+}
+
+/// A character read, and whether a backslash escaped it, which keeps it
+/// from acting as a field separator.
+pub type Char = (char, bool);
+
+/// Reads from standard input as `options` say. A backslash-newline pair is
+/// dropped unless the read is raw.
+pub fn read_input(options: &ReadOptions) -> io::Result<(Vec<Char>, End)> {
+    let _terminal = TerminalMode::set(options);
+    let deadline = options.timeout.map(|timeout| Instant::now() + timeout);
+    let mut bytes: Vec<(u8, bool)> = Vec::new();
+    let mut chars = 0;
+    let mut escaped = false;
+
+    let end = loop {
+        if options.count.is_some_and(|count| chars >= count) {
+            break End::Complete;
+        }
+        let Some(byte) = next_byte(options.buffered, deadline)? else {
+            break if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                End::TimedOut
+            } else {
+                End::Eof
+            };
+        };
+        if escaped {
+            escaped = false;
+            if byte != b'\n' {
+                bytes.push((byte, true));
+                chars += 1;
+            }
+            continue;
+        }
+        if byte == b'\\' && !options.raw {
+            escaped = true;
+            continue;
+        }
+        if byte == options.delimiter {
+            break End::Complete;
+        }
+        // The rest of a multi-byte character is read along with it, so a
+        // count never stops in the middle of one.
+        bytes.push((byte, false));
+        for _ in 1..utf8_length(byte) {
+            match next_byte(options.buffered, deadline)? {
+                Some(next) => bytes.push((next, false)),
+                None => break,
+            }
+        }
+        chars += 1;
+    };
+    Ok((decode(&bytes), end))
+}
+
+/// Whether input is ready on stdin within `timeout`, for `read -t 0`.
+pub fn input_ready(timeout: Duration) -> bool {
+    poll(timeout)
+}
+
+fn next_byte(buffered: bool, deadline: Option<Instant>) -> io::Result<Option<u8>> {
+    if buffered {
+        let mut stdin = io::stdin().lock();
+        loop {
+            // With a deadline the buffer is refilled without blocking, so
+            // when it is empty there is still time to wait for input.
+            let byte = match deadline {
+                Some(_) => nonblocking(|| stdin.fill_buf().map(|buf| buf.first().copied())),
+                None => stdin.fill_buf().map(|buf| buf.first().copied()),
+            };
+            match byte {
+                Ok(byte) => {
+                    if byte.is_some() {
+                        stdin.consume(1);
+                    }
+                    return Ok(byte);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let remaining = deadline.map_or(Duration::ZERO, |deadline| {
+                        deadline.saturating_duration_since(Instant::now())
+                    });
+                    if remaining.is_zero() {
+                        return Ok(None);
+                    }
+                    poll(remaining);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    if let Some(deadline) = deadline {
+        // `poll` counts whole milliseconds, so it may wake just short of
+        // the deadline.
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Ok(None);
+            }
+            if poll(remaining) {
+                break;
+            }
+        }
+    }
+    let mut byte = 0u8;
+    loop {
+        match unsafe { libc::read(libc::STDIN_FILENO, (&mut byte as *mut u8).cast(), 1) } {
+            1 => return Ok(Some(byte)),
+            0 => return Ok(None),
+            _ => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+        }
+    }
+}
+
+/// Runs `f` with stdin in non-blocking mode, so a read with nothing to
+/// return fails with `WouldBlock` instead of waiting.
+fn nonblocking<T>(f: impl FnOnce() -> T) -> T {
+    let flags = unsafe { libc::fcntl(libc::STDIN_FILENO, libc::F_GETFL) };
+    if flags < 0 || flags & libc::O_NONBLOCK != 0 {
+        return f();
+    }
+    unsafe { libc::fcntl(libc::STDIN_FILENO, libc::F_SETFL, flags | libc::O_NONBLOCK) };
+    let result = f();
+    unsafe { libc::fcntl(libc::STDIN_FILENO, libc::F_SETFL, flags) };
+    result
+}
+
+fn poll(timeout: Duration) -> bool {
+    let mut fd = libc::pollfd { fd: libc::STDIN_FILENO, events: libc::POLLIN, revents: 0 };
+    let millis = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    unsafe { libc::poll(&mut fd, 1, millis) > 0 }
+}
+
+fn utf8_length(lead: u8) -> usize {
+    match lead {
+        0xf0..=0xf7 => 4,
+        0xe0..=0xef => 3,
+        0xc0..=0xdf => 2,
+        _ => 1,
+    }
+}
+
+/// Turns the bytes read into characters, replacing invalid UTF-8.
+fn decode(bytes: &[(u8, bool)]) -> Vec<Char> {
+    let mut chars = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let (lead, escaped) = bytes[i];
+        let len = utf8_length(lead).min(bytes.len() - i);
+        let raw: Vec<u8> = bytes[i..i + len].iter().map(|&(byte, _)| byte).collect();
+        match std::str::from_utf8(&raw) {
+            Ok(text) => chars.extend(text.chars().map(|ch| (ch, escaped))),
+            Err(_) => chars.push((char::REPLACEMENT_CHARACTER, escaped)),
+        }
+        i += len;
+    }
+    chars
+}
+
+/// Puts a terminal on stdin into the mode `-s`, `-n` and `-d` need for the
+/// duration of the read: no echo, and characters delivered as they are
+/// typed rather than a line at a time.
+struct TerminalMode {
+    saved: Option<libc::termios>,
+}
+
+impl TerminalMode {
+    fn set(options: &ReadOptions) -> TerminalMode {
+        let raw_input = options.count.is_some() || options.delimiter != b'\n';
+        if !(options.silent || raw_input) || unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+            return TerminalMode { saved: None };
+        }
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return TerminalMode { saved: None };
+        }
+        let saved = termios;
+        if options.silent {
+            termios.c_lflag &= !(libc::ECHO | libc::ECHONL);
+        }
+        if raw_input {
+            termios.c_lflag &= !libc::ICANON;
+            termios.c_cc[libc::VMIN] = 1;
+            termios.c_cc[libc::VTIME] = 0;
+        }
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+        TerminalMode { saved: Some(saved) }
+    }
+}
+
+impl Drop for TerminalMode {
+    fn drop(&mut self) {
+        if let Some(saved) = &self.saved {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved) };
+        }
+    }
+}
+
+/// Splits `input` into `count` fields at unescaped characters in `ifs`, as
+/// `read name...` assigns them. Leading and trailing IFS whitespace is
+/// dropped, and the last field takes the rest of the input, separators and
+/// all, except for a single trailing delimiter.
+pub fn split_fields(input: &[Char], ifs: &str, count: usize) -> Vec<String> {
+    let mut end = input.len();
+    while end > 0 && (Splitter { input, ifs }).is_blank(end - 1) {
+        end -= 1;
+    }
+    let input = &input[..end];
+    let splitter = Splitter { input, ifs };
+
+    let mut fields = Vec::with_capacity(count);
+    let mut pos = splitter.skip_blanks(0);
+    for _ in 1..count {
+        let (field, next) = splitter.field(pos);
+        fields.push(field);
+        pos = next;
+    }
+    if count > 0 {
+        let (field, next) = splitter.field(pos);
+        let rest = if next == input.len() { field } else { text(&input[pos..]) };
+        fields.push(rest);
+    }
+    fields
+}
+
+/// Splits `input` into as many fields as it holds, for `read -a`.
+pub fn split_all(input: &[Char], ifs: &str) -> Vec<String> {
+    let splitter = Splitter { input, ifs };
+    let mut fields = Vec::new();
+    let mut pos = splitter.skip_blanks(0);
+    while pos < input.len() {
+        let (field, next) = splitter.field(pos);
+        fields.push(field);
+        pos = next;
+    }
+    fields
+}
+
+/// The characters of `input` as a string, escapes already removed.
+pub fn text(input: &[Char]) -> String {
+    input.iter().map(|&(ch, _)| ch).collect()
+}
+
+struct Splitter<'a> {
+    input: &'a [Char],
+    ifs: &'a str,
+}
+
+impl Splitter<'_> {
+    fn is_separator(&self, i: usize) -> bool {
+        let (ch, escaped) = self.input[i];
+        !escaped && self.ifs.contains(ch)
+    }
+
+    fn is_blank(&self, i: usize) -> bool {
+        self.is_separator(i) && self.input[i].0.is_whitespace()
+    }
+
+    fn skip_blanks(&self, mut pos: usize) -> usize {
+        while pos < self.input.len() && self.is_blank(pos) {
+            pos += 1;
+        }
+        pos
+    }
+
+    /// The field starting at `pos`, and where the next one starts: after
+    /// any IFS whitespace around at most one other separator.
+    fn field(&self, pos: usize) -> (String, usize) {
+        let mut end = pos;
+        while end < self.input.len() && !self.is_separator(end) {
+            end += 1;
+        }
+        let mut next = self.skip_blanks(end);
+        if next < self.input.len() && self.is_separator(next) && !self.is_blank(next) {
+            next = self.skip_blanks(next + 1);
+        }
+        (text(&self.input[pos..end]), next)
+    }
+}
@@ -0,0 +1,162 @@
+use std::env;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+use crate::options::Options;
+use crate::{source_file, tokenize, Shell};
+
+/// Sets up the variables the shell maintains itself: `PPID`, `SHLVL` one
+/// deeper than the parent's, `PWD`/`OLDPWD` for the starting directory, and
+/// in an interactive shell the history settings' defaults.
+pub fn init_variables(shell: &mut Shell) {
+    let _ = shell.vars.set("PPID", nix::unistd::getppid().to_string());
+    shell.vars.declare("PPID").readonly = true;
+
+    let level = shell.vars.get("SHLVL").and_then(|level| level.parse::<i64>().ok()).unwrap_or(0);
+    let _ = shell.vars.set("SHLVL", (level.max(0) + 1).to_string());
+    shell.vars.set_exported("SHLVL", true);
+
+    // An inherited PWD is kept only if it still names the current directory,
+    // so a logical path through a symlink survives.
+    if let Ok(cwd) = env::current_dir() {
+        let inherited = shell.vars.get("PWD").filter(|pwd| Path::new(pwd).is_absolute());
+        let same = inherited.is_some_and(|pwd| fs::canonicalize(pwd).ok() == fs::canonicalize(&cwd).ok());
+        if !same {
+            let _ = shell.vars.set("PWD", cwd.to_string_lossy().into_owned());
+        }
+        shell.vars.set_exported("PWD", true);
+    }
+    if !shell.vars.get("OLDPWD").is_some_and(|oldpwd| Path::new(oldpwd).is_dir()) {
+        let _ = shell.vars.unset("OLDPWD");
+    }
+    shell.vars.set_exported("OLDPWD", true);
+
+    if shell.interactive {
+        let histfile = shell.vars.get("HOME").map(|home| Path::new(home).join(".rustyshell_history"));
+        let defaults = [
+            ("HISTFILE", histfile.map(|path| path.to_string_lossy().into_owned())),
+            ("HISTSIZE", Some("500".to_string())),
+            ("HISTFILESIZE", Some("500".to_string())),
+        ];
+        for (name, value) in defaults {
+            if let (None, Some(value)) = (shell.vars.get(name), value) {
+                let _ = shell.vars.set(name, value);
+            }
+        }
+    }
+}
+
+/// How the shell was invoked, as decided from `argv` before anything runs.
+pub struct StartupOptions {
+    pub login: bool,
+    pub interactive: bool,
+    pub norc: bool,
+    pub noprofile: bool,
+    pub rcfile: Option<String>,
+    /// The `set` options turned on with their letters, such as `-n`.
+    pub set: Vec<&'static str>,
+    pub script: Option<String>,
+    pub script_args: Vec<String>,
+}
+
+impl StartupOptions {
+    /// Parses the command line. A leading `-` on `argv[0]` marks a login
+    /// shell, the same convention `login(1)` uses. The first non-option
+    /// argument names a script; everything after it becomes `$1`, `$2`, ….
+    pub fn parse(argv: &[String]) -> Result<Self, String> {
+        let mut options = StartupOptions {
+            login: argv.first().is_some_and(|arg0| arg0.starts_with('-')),
+            interactive: false,
+            norc: false,
+            noprofile: false,
+            rcfile: None,
+            set: Vec::new(),
+            script: None,
+            script_args: Vec::new(),
+        };
+        let mut force_interactive = false;
+
+        let mut args = argv.iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--login" => options.login = true,
+                "--norc" => options.norc = true,
+                "--noprofile" => options.noprofile = true,
+                "--check" => options.set.push("noexec"),
+                "--rcfile" | "--init-file" => match args.next() {
+                    Some(file) => options.rcfile = Some(file.clone()),
+                    None => return Err(format!("{}: option requires an argument", arg)),
+                },
+                "--" => {
+                    options.script = args.next().cloned();
+                    break;
+                }
+                _ if arg.starts_with("--") => return Err(format!("{}: invalid option", arg)),
+                _ if arg.starts_with('-') && arg.len() > 1 => {
+                    for flag in arg[1..].chars() {
+                        match flag {
+                            'l' => options.login = true,
+                            'i' => force_interactive = true,
+                            _ => match Options::name_of(flag) {
+                                Some(name) => options.set.push(name),
+                                None => return Err(format!("-{}: invalid option", flag)),
+                            },
+                        }
+                    }
+                }
+                _ => {
+                    options.script = Some(arg.clone());
+                    break;
+                }
+            }
+        }
+        options.script_args = args.cloned().collect();
+        options.interactive =
+            force_interactive || (options.script.is_none() && std::io::stdin().is_terminal());
+
+        Ok(options)
+    }
+}
+
+/// Sources the profile and rc files that apply to this kind of shell:
+/// `/etc/profile` and `~/.profile` for login shells, then `~/.rustyshellrc`
+/// (or `--rcfile`) and the file named by `$ENV` for interactive ones.
+pub fn load_startup_files(shell: &mut Shell, options: &StartupOptions) {
+    let home = shell.vars.get("HOME").map(PathBuf::from);
+
+    if options.login && !options.noprofile {
+        source_if_present(shell, Path::new("/etc/profile"));
+        if let Some(home) = &home {
+            source_if_present(shell, &home.join(".profile"));
+        }
+    }
+
+    if !options.interactive {
+        return;
+    }
+
+    if !options.norc && !options.login {
+        match &options.rcfile {
+            Some(rcfile) => source_if_present(shell, Path::new(rcfile)),
+            None => {
+                if let Some(home) = &home {
+                    source_if_present(shell, &home.join(".rustyshellrc"));
+                }
+            }
+        }
+    }
+
+    // POSIX: the value of ENV undergoes parameter expansion before use.
+    if let Some(env_file) = shell.vars.get("ENV").map(str::to_string) {
+        if let Some(path) = tokenize(&env_file, shell).ok().and_then(|words| words.into_iter().next()) {
+            source_if_present(shell, Path::new(&path));
+        }
+    }
+}
+
+fn source_if_present(shell: &mut Shell, path: &Path) {
+    if path.is_file() {
+        source_file(shell, path, &[]);
+    }
+}
@@ -0,0 +1,210 @@
+//! `trap`: the actions the shell runs when it receives a signal or reaches
+//! one of the pseudo-signals `EXIT`, `ERR`, `DEBUG` and `RETURN`.
+//!
+//! A caught signal only sets a flag; the main loop notices it between
+//! commands and runs the action there, so nothing but an atomic store
+//! happens inside the handler.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The signals `trap` knows by name, as `kill -l` numbers them.
+const SIGNALS: [(&str, libc::c_int); 29] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    ("SYS", libc::SIGSYS),
+];
+
+/// One more than the highest signal number a flag is kept for.
+const MAX_SIGNAL: usize = 65;
+
+/// Signals that arrived and have not had their action run yet.
+static PENDING: [AtomicBool; MAX_SIGNAL] = [const { AtomicBool::new(false) }; MAX_SIGNAL];
+
+extern "C" fn record(signal: libc::c_int) {
+    if let Some(flag) = PENDING.get(signal as usize) {
+        flag.store(true, Ordering::SeqCst);
+    }
+}
+
+/// What a trap is set on. The derived order is the order `trap -p` lists
+/// them in: `EXIT`, the signals by number, then `DEBUG`, `ERR`, `RETURN`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Condition {
+    Exit,
+    Signal(libc::c_int),
+    Debug,
+    Err,
+    Return,
+}
+
+impl Condition {
+    /// Parses a signal specification: a name with or without its `SIG`
+    /// prefix, in any case, or a number, where 0 means `EXIT`.
+    pub fn parse(spec: &str) -> Option<Condition> {
+        if let Ok(number) = spec.parse::<libc::c_int>() {
+            return match number {
+                0 => Some(Condition::Exit),
+                _ => SIGNALS.iter().any(|&(_, signal)| signal == number).then_some(Condition::Signal(number)),
+            };
+        }
+        let upper = spec.to_ascii_uppercase();
+        match upper.as_str() {
+            "EXIT" => return Some(Condition::Exit),
+            "DEBUG" => return Some(Condition::Debug),
+            "ERR" => return Some(Condition::Err),
+            "RETURN" => return Some(Condition::Return),
+            _ => {}
+        }
+        let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+        SIGNALS
+            .iter()
+            .find(|(signal_name, _)| *signal_name == name)
+            .map(|&(_, signal)| Condition::Signal(signal))
+    }
+
+    /// The name `trap -p` prints, such as `SIGINT` or `EXIT`.
+    pub fn name(&self) -> String {
+        match self {
+            Condition::Exit => "EXIT".to_string(),
+            Condition::Signal(signal) => format!("SIG{}", signal_name(*signal)),
+            Condition::Debug => "DEBUG".to_string(),
+            Condition::Err => "ERR".to_string(),
+            Condition::Return => "RETURN".to_string(),
+        }
+    }
+}
+
+fn signal_name(signal: libc::c_int) -> &'static str {
+    SIGNALS
+        .iter()
+        .find(|&&(_, number)| number == signal)
+        .map_or("", |(name, _)| *name)
+}
+
+/// The signal list `trap -l` prints, five to a line.
+pub fn list() -> String {
+    let mut signals = SIGNALS.to_vec();
+    signals.sort_by_key(|&(_, number)| number);
+    let mut out = String::new();
+    for (i, (name, number)) in signals.iter().enumerate() {
+        out.push_str(&format!("{:>2}) SIG{}", number, name));
+        out.push(if (i + 1) % 5 == 0 || i + 1 == signals.len() { '\n' } else { '\t' });
+    }
+    out
+}
+
+/// The trap actions in effect. An empty action means the signal is ignored.
+#[derive(Default)]
+pub struct Traps {
+    actions: BTreeMap<Condition, String>,
+    /// Set while an action runs, so `ERR` and `DEBUG` don't fire inside it.
+    pub running: bool,
+}
+
+impl Traps {
+    pub fn get(&self, condition: Condition) -> Option<&str> {
+        self.actions.get(&condition).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Condition, &str)> {
+        self.actions.iter().map(|(condition, action)| (*condition, action.as_str()))
+    }
+
+    /// Sets the action for `condition`, catching or ignoring the signal.
+    pub fn set(&mut self, condition: Condition, action: &str) {
+        if let Condition::Signal(signal) = condition {
+            if action.is_empty() {
+                disposition(signal, libc::SIG_IGN);
+            } else {
+                disposition(signal, record as extern "C" fn(libc::c_int) as libc::sighandler_t);
+            }
+        }
+        self.actions.insert(condition, action.to_string());
+    }
+
+    /// Removes the action for `condition`, giving the signal back its
+    /// default behaviour.
+    pub fn reset(&mut self, condition: Condition) {
+        if self.actions.remove(&condition).is_some() {
+            if let Condition::Signal(signal) = condition {
+                disposition(signal, libc::SIG_DFL);
+            }
+        }
+    }
+
+    /// Removes the action for `condition` and returns it, so the `EXIT`
+    /// trap runs only once.
+    pub fn take(&mut self, condition: Condition) -> Option<String> {
+        self.actions.remove(&condition)
+    }
+
+    /// Adjusts the traps in a forked subshell: caught signals go back to
+    /// their defaults and the pseudo-signals are cleared, while ignored
+    /// signals stay ignored.
+    pub fn reset_for_subshell(&mut self) {
+        let conditions: Vec<Condition> = self.actions.keys().copied().collect();
+        for condition in conditions {
+            let ignored = matches!(condition, Condition::Signal(_)) && self.actions[&condition].is_empty();
+            if !ignored {
+                self.reset(condition);
+            }
+        }
+    }
+
+    /// The signals set to be ignored, which a command the shell runs must
+    /// inherit as ignored.
+    pub fn ignored(&self) -> Vec<libc::c_int> {
+        self.actions
+            .iter()
+            .filter_map(|(condition, action)| match condition {
+                Condition::Signal(signal) if action.is_empty() => Some(*signal),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Takes the signals that have arrived since the last call, lowest first.
+pub fn take_pending() -> Vec<libc::c_int> {
+    (1..MAX_SIGNAL)
+        .filter(|&signal| PENDING[signal].swap(false, Ordering::SeqCst))
+        .map(|signal| signal as libc::c_int)
+        .collect()
+}
+
+fn disposition(signal: libc::c_int, handler: libc::sighandler_t) {
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handler;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(signal, &action, std::ptr::null_mut());
+    }
+}
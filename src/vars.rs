@@ -0,0 +1,445 @@
+use std::collections::BTreeMap;
+use std::env;
+
+use thiserror::Error;
+
+use crate::arith;
+
+/// What a variable holds. Indexed arrays are sparse, so both kinds of array
+/// are kept as ordered maps.
+#[derive(Clone, Debug)]
+pub enum Value {
+    Scalar(String),
+    Indexed(BTreeMap<usize, String>),
+    Assoc(BTreeMap<String, String>),
+}
+
+impl Value {
+    /// The value seen when the variable is referenced without a subscript,
+    /// which for arrays is element `0`.
+    pub fn scalar(&self) -> Option<&str> {
+        match self {
+            Value::Scalar(value) => Some(value),
+            Value::Indexed(elements) => elements.get(&0).map(String::as_str),
+            Value::Assoc(elements) => elements.get("0").map(String::as_str),
+        }
+    }
+
+    /// Every element, in index or key order; a scalar is a one-element list.
+    pub fn values(&self) -> Vec<String> {
+        match self {
+            Value::Scalar(value) => vec![value.clone()],
+            Value::Indexed(elements) => elements.values().cloned().collect(),
+            Value::Assoc(elements) => elements.values().cloned().collect(),
+        }
+    }
+
+    /// The subscripts in use, as `${!name[@]}` lists them.
+    pub fn keys(&self) -> Vec<String> {
+        match self {
+            Value::Scalar(_) => vec!["0".to_string()],
+            Value::Indexed(elements) => elements.keys().map(usize::to_string).collect(),
+            Value::Assoc(elements) => elements.keys().cloned().collect(),
+        }
+    }
+}
+
+/// A single shell variable. A variable can carry attributes while still being
+/// unset (`export FOO` or `declare -i N` before anything is assigned), so the
+/// value is optional.
+#[derive(Clone, Debug, Default)]
+pub struct Variable {
+    pub value: Option<Value>,
+    pub exported: bool,
+    pub readonly: bool,
+    /// Assignments are evaluated as arithmetic expressions (`declare -i`).
+    pub integer: bool,
+    /// Assigned values are converted to lower case (`declare -l`).
+    pub lowercase: bool,
+    /// Assigned values are converted to upper case (`declare -u`).
+    pub uppercase: bool,
+}
+
+impl Variable {
+    /// The attribute letters `declare -p` shows for this variable, in the
+    /// order bash prints them.
+    pub fn flags(&self) -> String {
+        [
+            (matches!(self.value, Some(Value::Indexed(_))), 'a'),
+            (matches!(self.value, Some(Value::Assoc(_))), 'A'),
+            (self.integer, 'i'),
+            (self.lowercase, 'l'),
+            (self.readonly, 'r'),
+            (self.uppercase, 'u'),
+            (self.exported, 'x'),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| *flag)
+        .collect()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum VarError {
+    #[error("{0}: readonly variable")]
+    Readonly(String),
+    #[error("{0}: cannot unset: readonly variable")]
+    UnsetReadonly(String),
+    #[error("{0}: bad array subscript")]
+    BadSubscript(String),
+    #[error("{0}: must use subscript when assigning associative array")]
+    AssocNeedsSubscript(String),
+    #[error("{0}: cannot convert {1} to {2} array")]
+    Convert(String, &'static str, &'static str),
+    #[error("{0}")]
+    Arithmetic(String),
+}
+
+/// The shell's variable table. Variables inherited from the environment start
+/// out exported; everything else is local to the shell until `export`ed.
+#[derive(Clone, Debug, Default)]
+pub struct Variables {
+    table: BTreeMap<String, Variable>,
+}
+
+impl Variables {
+    pub fn from_env() -> Self {
+        let table = env::vars_os()
+            .filter_map(|(name, value)| {
+                let name = name.into_string().ok()?;
+                is_valid_name(&name).then(|| {
+                    let value = Value::Scalar(value.to_string_lossy().into_owned());
+                    (name, Variable { value: Some(value), exported: true, ..Default::default() })
+                })
+            })
+            .collect();
+        Variables { table }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.table.get(name)?.value.as_ref()?.scalar()
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&Variable> {
+        self.table.get(name)
+    }
+
+    /// Returns the variable called `name`, creating it unset and without
+    /// attributes if needed, so a builtin can change its attributes.
+    pub fn declare(&mut self, name: &str) -> &mut Variable {
+        self.table.entry(name.to_string()).or_default()
+    }
+
+    fn writable(&self, name: &str) -> Result<Option<&Variable>, VarError> {
+        match self.table.get(name) {
+            Some(var) if var.readonly => Err(VarError::Readonly(name.to_string())),
+            var => Ok(var),
+        }
+    }
+
+    /// Assigns `value`, keeping and applying any attributes the variable
+    /// already has. Assigning to an array without a subscript sets element
+    /// `0`, as in bash.
+    pub fn set(&mut self, name: &str, value: impl Into<String>) -> Result<(), VarError> {
+        match self.writable(name)?.and_then(|var| var.value.as_ref()) {
+            Some(Value::Indexed(_) | Value::Assoc(_)) => self.set_element(name, "0", value.into()),
+            _ => {
+                let value = self.apply_attributes(name, value.into())?;
+                self.declare(name).value = Some(Value::Scalar(value));
+                Ok(())
+            }
+        }
+    }
+
+    /// `NAME+=value`: appends to the string, or adds for integer variables.
+    pub fn append(&mut self, name: &str, value: &str) -> Result<(), VarError> {
+        match self.writable(name)?.and_then(|var| var.value.as_ref()) {
+            Some(Value::Indexed(_) | Value::Assoc(_)) => self.append_element(name, "0", value),
+            _ => {
+                let current = self.get(name).unwrap_or_default();
+                let combined = self.combine(name, current, value);
+                self.set(name, combined)
+            }
+        }
+    }
+
+    fn combine(&self, name: &str, current: &str, value: &str) -> String {
+        if self.table.get(name).is_some_and(|var| var.integer) {
+            format!("({}) + ({})", if current.is_empty() { "0" } else { current }, value)
+        } else {
+            format!("{}{}", current, value)
+        }
+    }
+
+    fn apply_attributes(&self, name: &str, value: String) -> Result<String, VarError> {
+        let Some(var) = self.table.get(name) else {
+            return Ok(value);
+        };
+        let value = if var.integer {
+            let lookup = |name: &str| self.get(name).map(str::to_string);
+            arith::evaluate(&value, &lookup).map_err(VarError::Arithmetic)?.to_string()
+        } else {
+            value
+        };
+        Ok(if var.lowercase {
+            value.to_lowercase()
+        } else if var.uppercase {
+            value.to_uppercase()
+        } else {
+            value
+        })
+    }
+
+    /// Resolves an indexed-array subscript: an arithmetic expression, where
+    /// negative values count back from one past the highest index.
+    fn index(&self, name: &str, subscript: &str) -> Result<usize, VarError> {
+        let lookup = |name: &str| self.get(name).map(str::to_string);
+        let index = arith::evaluate(subscript, &lookup).map_err(VarError::Arithmetic)?;
+        if index >= 0 {
+            return Ok(index as usize);
+        }
+        let end = match self.table.get(name).and_then(|var| var.value.as_ref()) {
+            Some(Value::Indexed(elements)) => elements.keys().next_back().map_or(0, |last| last + 1),
+            Some(Value::Scalar(_)) => 1,
+            _ => 0,
+        };
+        usize::try_from(end as i64 + index)
+            .map_err(|_| VarError::BadSubscript(format!("{}[{}]", name, subscript)))
+    }
+
+    pub fn is_assoc(&self, name: &str) -> bool {
+        matches!(self.table.get(name).and_then(|var| var.value.as_ref()), Some(Value::Assoc(_)))
+    }
+
+    pub fn get_element(&self, name: &str, subscript: &str) -> Result<Option<String>, VarError> {
+        let Some(value) = self.table.get(name).and_then(|var| var.value.as_ref()) else {
+            return Ok(None);
+        };
+        Ok(match value {
+            Value::Assoc(elements) => elements.get(subscript).cloned(),
+            Value::Indexed(elements) => elements.get(&self.index(name, subscript)?).cloned(),
+            Value::Scalar(scalar) => (self.index(name, subscript)? == 0).then(|| scalar.clone()),
+        })
+    }
+
+    /// `NAME[subscript]=value`. A scalar or unset variable becomes an
+    /// indexed array, with any old scalar value kept as element `0`.
+    pub fn set_element(&mut self, name: &str, subscript: &str, value: String) -> Result<(), VarError> {
+        self.writable(name)?;
+        let value = self.apply_attributes(name, value)?;
+        if self.is_assoc(name) {
+            if subscript.is_empty() {
+                return Err(VarError::BadSubscript(format!("{}[{}]", name, subscript)));
+            }
+            if let Some(Value::Assoc(elements)) = &mut self.declare(name).value {
+                elements.insert(subscript.to_string(), value);
+            }
+            return Ok(());
+        }
+
+        let index = self.index(name, subscript)?;
+        let var = self.declare(name);
+        let mut elements = match var.value.take() {
+            Some(Value::Indexed(elements)) => elements,
+            Some(Value::Scalar(scalar)) => BTreeMap::from([(0, scalar)]),
+            _ => BTreeMap::new(),
+        };
+        elements.insert(index, value);
+        var.value = Some(Value::Indexed(elements));
+        Ok(())
+    }
+
+    /// `NAME[subscript]+=value`.
+    pub fn append_element(&mut self, name: &str, subscript: &str, value: &str) -> Result<(), VarError> {
+        let current = self.get_element(name, subscript)?.unwrap_or_default();
+        let combined = self.combine(name, &current, value);
+        self.set_element(name, subscript, combined)
+    }
+
+    /// `NAME=(...)` and `NAME+=(...)`. Elements with an explicit `[subscript]=`
+    /// go there; the rest of an indexed array's elements follow on from the
+    /// previous index.
+    pub fn set_array(
+        &mut self,
+        name: &str,
+        elements: Vec<(Option<String>, String)>,
+        append: bool,
+    ) -> Result<(), VarError> {
+        self.writable(name)?;
+        let assoc = self.is_assoc(name);
+        let var = self.declare(name);
+        var.value = match (var.value.take(), append) {
+            (Some(Value::Assoc(elements)), true) => Some(Value::Assoc(elements)),
+            (_, _) if assoc => Some(Value::Assoc(BTreeMap::new())),
+            (Some(Value::Indexed(elements)), true) => Some(Value::Indexed(elements)),
+            (Some(Value::Scalar(scalar)), true) => Some(Value::Indexed(BTreeMap::from([(0, scalar)]))),
+            _ => Some(Value::Indexed(BTreeMap::new())),
+        };
+
+        let mut next = match &var.value {
+            Some(Value::Indexed(elements)) => elements.keys().next_back().map_or(0, |last| last + 1),
+            _ => 0,
+        };
+        for (subscript, value) in elements {
+            match subscript {
+                Some(subscript) if assoc => self.set_element(name, &subscript, value)?,
+                None if assoc => return Err(VarError::AssocNeedsSubscript(name.to_string())),
+                Some(subscript) => {
+                    next = self.index(name, &subscript)?;
+                    self.set_element(name, &next.to_string(), value)?;
+                    next += 1;
+                }
+                None => {
+                    self.set_element(name, &next.to_string(), value)?;
+                    next += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives `name` the array type `declare -a` or `declare -A` asks for. A
+    /// scalar value becomes element 0; an array of the other kind is an error
+    /// unless it is empty.
+    pub fn make_array(&mut self, name: &str, assoc: bool) -> Result<(), VarError> {
+        let var = self.declare(name);
+        let value = match (var.value.take(), assoc) {
+            (None, false) => Value::Indexed(BTreeMap::new()),
+            (None, true) => Value::Assoc(BTreeMap::new()),
+            (Some(Value::Scalar(value)), false) => Value::Indexed(BTreeMap::from([(0, value)])),
+            (Some(Value::Scalar(value)), true) => Value::Assoc(BTreeMap::from([("0".to_string(), value)])),
+            (Some(Value::Indexed(elements)), true) if !elements.is_empty() => {
+                var.value = Some(Value::Indexed(elements));
+                return Err(VarError::Convert(name.to_string(), "indexed", "associative"));
+            }
+            (Some(Value::Assoc(elements)), false) if !elements.is_empty() => {
+                var.value = Some(Value::Assoc(elements));
+                return Err(VarError::Convert(name.to_string(), "associative", "indexed"));
+            }
+            (Some(Value::Indexed(_)), true) => Value::Assoc(BTreeMap::new()),
+            (Some(Value::Assoc(_)), false) => Value::Indexed(BTreeMap::new()),
+            (Some(array), _) => array,
+        };
+        var.value = Some(value);
+        Ok(())
+    }
+
+    pub fn set_exported(&mut self, name: &str, exported: bool) {
+        if exported {
+            self.declare(name).exported = true;
+        } else if let Some(var) = self.table.get_mut(name) {
+            var.exported = false;
+        }
+    }
+
+    pub fn unset(&mut self, name: &str) -> Result<(), VarError> {
+        if self.table.get(name).is_some_and(|var| var.readonly) {
+            return Err(VarError::UnsetReadonly(name.to_string()));
+        }
+        self.table.remove(name);
+        Ok(())
+    }
+
+    /// `unset 'NAME[subscript]'`.
+    pub fn unset_element(&mut self, name: &str, subscript: &str) -> Result<(), VarError> {
+        if self.table.get(name).is_some_and(|var| var.readonly) {
+            return Err(VarError::UnsetReadonly(name.to_string()));
+        }
+        let index = if self.is_assoc(name) { None } else { Some(self.index(name, subscript)?) };
+        let Some(var) = self.table.get_mut(name) else {
+            return Ok(());
+        };
+        match (&mut var.value, index) {
+            (Some(Value::Assoc(elements)), _) => {
+                elements.remove(subscript);
+            }
+            (Some(Value::Indexed(elements)), Some(index)) => {
+                elements.remove(&index);
+            }
+            (Some(Value::Scalar(_)), Some(0)) => {
+                self.table.remove(name);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Puts back a variable captured with [`Variables::lookup`], or removes
+    /// it if it did not exist. Used to undo per-command assignments.
+    pub fn restore(&mut self, name: &str, previous: Option<Variable>) {
+        match previous {
+            Some(var) => {
+                self.table.insert(name.to_string(), var);
+            }
+            None => {
+                self.table.remove(name);
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Variable)> {
+        self.table.iter().map(|(name, var)| (name.as_str(), var))
+    }
+
+    /// The `NAME`/`value` pairs handed to child processes as their
+    /// environment. Arrays cannot be exported.
+    pub fn exported(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.table.iter().filter_map(|(name, var)| match (&var.value, var.exported) {
+            (Some(Value::Scalar(value)), true) => Some((name.as_str(), value.as_str())),
+            _ => None,
+        })
+    }
+}
+
+/// Whether `name` is a valid shell variable name: a letter or underscore
+/// followed by letters, digits and underscores.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Formats a variable the way `declare -p` prints it, so the output can be
+/// fed back to the shell to recreate the variable.
+pub fn declaration(name: &str, var: &Variable) -> String {
+    let flags = var.flags();
+    let flags = if flags.is_empty() { "--".to_string() } else { format!("-{}", flags) };
+    match assignment(name, var) {
+        Some(assignment) => format!("declare {} {}", flags, assignment),
+        None => format!("declare {} {}", flags, name),
+    }
+}
+
+/// `NAME=value` with the value quoted for re-use, as `set` lists variables;
+/// `None` if the variable has no value.
+pub fn assignment(name: &str, var: &Variable) -> Option<String> {
+    let value = match var.value.as_ref()? {
+        Value::Scalar(value) => double_quote(value),
+        Value::Indexed(elements) => compound(elements.iter().map(|(i, v)| (i.to_string(), v))),
+        Value::Assoc(elements) => compound(elements.iter().map(|(k, v)| (double_quote(k), v))),
+    };
+    Some(format!("{}={}", name, value))
+}
+
+fn compound<'a>(elements: impl Iterator<Item = (String, &'a String)>) -> String {
+    let elements: Vec<String> = elements
+        .map(|(key, value)| format!("[{}]={}", key, double_quote(value)))
+        .collect();
+    format!("({})", elements.join(" "))
+}
+
+/// Quotes `value` so it can be read back by the shell inside double quotes,
+/// as used by `declare -p`.
+pub fn double_quote(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for ch in value.chars() {
+        if matches!(ch, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(ch);
+    }
+    quoted.push('"');
+    quoted
+}
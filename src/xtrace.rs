@@ -0,0 +1,47 @@
+//! `set -x`: printing each command, after expansion, before it runs.
+
+use std::os::unix::io::RawFd;
+
+use crate::{expand_string, Shell};
+
+/// Prints `words`, which the caller has already quoted with [`quote`], as
+/// one trace line. The line starts with the expanded `$PS4`, its first
+/// character repeated once more for each level of `source` nesting. It goes
+/// to the file descriptor named by `$BASH_XTRACEFD` if that is open, and to
+/// stderr otherwise.
+pub fn trace(shell: &mut Shell, words: &[String]) {
+    if !shell.options.xtrace {
+        return;
+    }
+
+    let ps4 = shell.vars.get("PS4").unwrap_or("+ ").to_string();
+    let ps4 = expand_string(&ps4, shell).unwrap_or(ps4);
+    let mut line: String = ps4.chars().take(1).cycle().take(shell.nesting()).collect();
+    line.push_str(&ps4);
+    line.push_str(&words.join(" "));
+    line.push('\n');
+
+    let fd = shell
+        .vars
+        .get("BASH_XTRACEFD")
+        .and_then(|fd| fd.parse::<RawFd>().ok())
+        .filter(|&fd| fd >= 0 && unsafe { libc::fcntl(fd, libc::F_GETFD) } != -1)
+        .unwrap_or(libc::STDERR_FILENO);
+    unsafe {
+        libc::write(fd, line.as_ptr().cast(), line.len());
+    }
+}
+
+/// Quotes `word` for a trace line so it reads back as the same word: in
+/// single quotes if it holds anything beyond plain word characters.
+pub fn quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_alphanumeric() || "-_./,:=+@%^".contains(c));
+    if plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "'\\''"))
+    }
+}